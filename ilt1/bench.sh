@@ -0,0 +1,47 @@
+#!/usr/bin/env bash
+# Times bench.thing, which runs the functions in examples.thing in a loop,
+# with the interpreter built from the working tree and from another
+# revision, to compare the two.
+#
+#   ./bench.sh revision [runs]
+#
+# The revision has to be given, since no fixed one is a good baseline for
+# long. Runs default to 5. The fastest run of each is reported.
+set -euo pipefail
+
+if [[ $# -lt 1 ]]; then
+    echo "usage: $0 revision [runs]" >&2
+    exit 2
+fi
+
+cd "$(dirname "$0")"
+revision=$1
+runs=${2:-5}
+
+# Build the other revision in a worktree of its own.
+before=$(mktemp -d)
+trap 'git worktree remove --force "$before"' EXIT
+git worktree add --quiet --detach "$before" "$revision"
+prefix=$(git rev-parse --show-prefix)
+
+cargo build --quiet --release
+cargo build --quiet --release --manifest-path "$before/${prefix}Cargo.toml"
+
+# Prints the fastest of `runs` runs of the interpreter at $2, in ms.
+time_runs() {
+    local best=
+    for _ in $(seq "$runs"); do
+        local start end
+        start=$(date +%s%N)
+        "$2" bench.thing > /dev/null
+        end=$(date +%s%N)
+        local ms=$(((end - start) / 1000000))
+        if [[ -z $best || $ms -lt $best ]]; then
+            best=$ms
+        fi
+    done
+    printf '%-8s %6d ms\n' "$1" "$best"
+}
+
+time_runs "$revision" "$before/${prefix}target/release/ilt1"
+time_runs working "target/release/ilt1"
@@ -1,11 +1,25 @@
+use crate::diagnostic::Span;
 use crate::token::Keyword;
 
 /// An abstract syntax tree node
 #[derive(Debug, Clone, PartialEq)]
 pub struct AstNode {
     pub ty: AstNodeType,
-    pub line: usize,
-    pub col: usize,
+    pub span: Span,
+}
+
+/// Where a variable is stored. The parser leaves every variable
+/// `Unresolved`, and the resolver fills in the frame and index it lives at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Slot {
+    /// Looked up by name in the top scope.
+    #[default]
+    Unresolved,
+    /// A variable of the top scope, by its index there.
+    Global(usize),
+    /// A variable of a block or function call, `depth` frames out from the
+    /// current one.
+    Local { depth: usize, index: usize },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -14,23 +28,29 @@ pub enum AstNodeType {
     Float(f64),
     String(String),
     Bool(bool),
-    Ident(String),
+    Ident(String, Slot),
     Keyword(Keyword),
+    /// A function definition. Anonymous functions (lambdas) have no name and
+    /// are not bound in the enclosing scope.
     Fn {
-        name: String,
+        name: Option<String>,
+        slot: Slot,
         params: Vec<AstNode>,
         body: Box<AstNode>,
     },
     Const {
         name: String,
+        slot: Slot,
         value: Box<AstNode>,
     },
     Let {
         name: String,
+        slot: Slot,
         value: Box<AstNode>,
     },
     Set {
         name: String,
+        slot: Slot,
         value: Box<AstNode>,
     },
     If {
@@ -42,13 +62,32 @@ pub enum AstNodeType {
         condition: Box<AstNode>,
         body: Box<AstNode>,
     },
+    /// `for name in iterable { ... }`. Each iteration binds `name` in a frame
+    /// of its own, which the body's frame is a child of.
+    For {
+        name: String,
+        iterable: Box<AstNode>,
+        body: Box<AstNode>,
+    },
+    Return(Option<Box<AstNode>>),
+    Break,
+    Continue,
     Main(Box<AstNode>),
+    /// `import "path.thing"`, optionally followed by `as alias`. The alias
+    /// defaults to the file name without its extension.
+    Import {
+        path: String,
+        alias: String,
+    },
     Call {
         name: String,
+        slot: Slot,
         params: Vec<AstNode>,
     },
     Block(Vec<AstNode>),
     Array(Vec<AstNode>),
+    /// `#{key value, ...}`, as key and value pairs.
+    Dict(Vec<(AstNode, AstNode)>),
 }
 
 impl AstNodeType {
@@ -56,14 +95,17 @@ impl AstNodeType {
         match keyword {
             Keyword::Const => Self::Const {
                 name,
+                slot: Slot::Unresolved,
                 value: Box::new(value),
             },
             Keyword::Let => Self::Let {
                 name,
+                slot: Slot::Unresolved,
                 value: Box::new(value),
             },
             Keyword::Set => Self::Set {
                 name,
+                slot: Slot::Unresolved,
                 value: Box::new(value),
             },
             _ => unreachable!(),
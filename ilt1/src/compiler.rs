@@ -0,0 +1,532 @@
+use std::{fmt, rc::Rc};
+
+use crate::ast::{AstNode, AstNodeType, Slot};
+use crate::diagnostic::Span;
+use crate::interpreter::{self, InterpreterError, InterpreterValue, ANONYMOUS_FUNCTION_NAME};
+
+/// An instruction for the VM. Instructions take their operands from the top
+/// of the VM's stack and push their results onto it. Names are indices into
+/// `Chunk::names`.
+#[derive(Debug, Clone)]
+pub enum Op {
+    /// Pushes a literal.
+    Constant(Rc<InterpreterValue>),
+    /// Pushes the value of a variable.
+    Load(usize, Slot),
+    /// Defines a variable as the value on top of the stack, leaving it there.
+    Define(usize, Slot),
+    /// Defines a constant in the same way.
+    DefineConstant(usize, Slot),
+    /// Overwrites an existing variable with the value on top of the stack,
+    /// leaving it there.
+    Assign(usize, Slot),
+    Pop,
+    /// Removes the value under the top one.
+    Nip,
+    /// Pops that many values into an array.
+    Array(usize),
+    /// Checks that the value on top of the stack can be a dict key.
+    Key,
+    /// Pops that many keys and values into a dict.
+    Dict(usize),
+    /// Pushes a function that captures the current frame.
+    Function(Rc<CompiledFunction>),
+    /// Pushes the function a call with `args` arguments is made to, checking
+    /// that it takes that many.
+    Callee { name: usize, slot: Slot, args: usize },
+    /// Calls the function under the arguments on top of the stack. Natives
+    /// read their arguments through these nodes.
+    Call(Rc<Vec<AstNode>>),
+    /// A call whose result is the current function's result. Calls to
+    /// compiled functions reuse the current function's frame.
+    TailCall(Rc<Vec<AstNode>>),
+    /// Returns the value on top of the stack from the current function.
+    Return,
+    Jump(usize),
+    /// Pops a condition and jumps if it is false. The keyword is used in the
+    /// error for a condition that isn't a bool.
+    JumpUnless(usize, &'static str),
+    /// Pops the callee on top of the stack if it is the native with this
+    /// name, and otherwise jumps, leaving it there.
+    JumpUnlessNative(usize, usize),
+    /// Replaces the value on top of the stack with an array of the items a
+    /// `for` loop over it goes through.
+    Items,
+    /// Replaces that many arguments of `range` with its end, step and start,
+    /// which a `for` loop goes through in place of the items, their unused
+    /// step and the position in them.
+    Range(usize),
+    /// Pushes the next item of a `for` loop and moves past it, or jumps if
+    /// there are none left. The items or end, the step and the position are
+    /// under the value on top of the stack.
+    Next(usize),
+    /// Enters a block's frame.
+    PushFrame,
+    /// Leaves a block's frame.
+    PopFrame,
+    /// Drops values until the current function has that many on the stack.
+    Truncate(usize),
+    /// Raises an error, for nodes that can't be run where they are.
+    Error(Box<InterpreterError>),
+}
+
+/// Compiled code, along with the span of the node each instruction was
+/// compiled from.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Span>,
+    pub names: Vec<String>,
+}
+
+/// A function body, or top level code, compiled to bytecode. It is shared by
+/// every function value made from the same definition.
+pub struct CompiledFunction {
+    pub name: String,
+    pub params: Vec<String>,
+    pub chunk: Chunk,
+}
+
+impl fmt::Debug for CompiledFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledFunction")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Compiles a function whose body is `body`.
+pub fn compile_function(name: String, params: Vec<String>, body: &AstNode) -> CompiledFunction {
+    let mut compiler = Compiler {
+        function: true,
+        ..Default::default()
+    };
+    compiler.compile(body, true);
+    compiler.emit(Op::Return, body.span);
+    CompiledFunction {
+        name,
+        params,
+        chunk: compiler.chunk,
+    }
+}
+
+/// Compiles code that runs directly in the top frame, such as `main`'s body
+/// or an input to the REPL.
+pub fn compile_top_level(node: &AstNode) -> CompiledFunction {
+    let mut compiler = Compiler::default();
+    compiler.compile(node, false);
+    compiler.emit(Op::Return, node.span);
+    CompiledFunction {
+        name: "<top level>".to_owned(),
+        params: Vec::new(),
+        chunk: compiler.chunk,
+    }
+}
+
+/// A `while` or `for` loop being compiled.
+struct Loop {
+    /// Where each iteration starts.
+    start: usize,
+    /// The stack height and the number of open frames at the start of each
+    /// iteration, which `break` and `continue` go back to.
+    height: usize,
+    frames: usize,
+    /// The jumps made by `break`, to point at the end of the loop once it is
+    /// known.
+    breaks: Vec<usize>,
+}
+
+/// Compiles one function. The stack height is tracked as code is compiled,
+/// so `break` and `continue` know how many values to drop.
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+    /// How many values the function has on the stack after the code so far.
+    height: usize,
+    /// How many block frames are open.
+    frames: usize,
+    loops: Vec<Loop>,
+    /// Whether this is a function's body, rather than top level code whose
+    /// `return` can't replace its frame with a tail call.
+    function: bool,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.height = match &op {
+            Op::Constant(_)
+            | Op::Load(..)
+            | Op::Function(_)
+            | Op::Callee { .. }
+            | Op::Next(_)
+            | Op::Error(_) => self.height + 1,
+            Op::Pop | Op::Nip | Op::JumpUnless(..) | Op::JumpUnlessNative(..) => self.height - 1,
+            Op::Array(len) => self.height + 1 - len,
+            Op::Dict(len) => self.height + 1 - 2 * len,
+            Op::Call(args) | Op::TailCall(args) => self.height - args.len(),
+            Op::Range(args) => self.height + 3 - args,
+            Op::Truncate(height) => *height,
+            // `return` is an expression, so it counts as leaving its value
+            // even though nothing after it runs.
+            Op::Define(..)
+            | Op::DefineConstant(..)
+            | Op::Assign(..)
+            | Op::Key
+            | Op::Items
+            | Op::Return
+            | Op::Jump(_)
+            | Op::PushFrame
+            | Op::PopFrame => self.height,
+        };
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn constant(&mut self, value: InterpreterValue, span: Span) {
+        self.emit(Op::Constant(Rc::new(value)), span);
+    }
+
+    fn error(&mut self, error: InterpreterError, span: Span) {
+        self.emit(Op::Error(Box::new(error)), span);
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        match self.chunk.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.chunk.names.push(name.to_owned());
+                self.chunk.names.len() - 1
+            }
+        }
+    }
+
+    /// Points the jump at `index` to the next instruction.
+    fn patch(&mut self, index: usize) {
+        let next = self.chunk.code.len();
+        match &mut self.chunk.code[index] {
+            Op::Jump(target)
+            | Op::JumpUnless(target, _)
+            | Op::JumpUnlessNative(_, target)
+            | Op::Next(target) => *target = next,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Compiles a node that leaves one value on the stack. `tail` is whether
+    /// the value is the result of the function, as in the tree-walker.
+    fn compile(&mut self, node: &AstNode, tail: bool) {
+        let span = node.span;
+        match &node.ty {
+            AstNodeType::Int(value) => self.constant(InterpreterValue::Int(*value), span),
+            AstNodeType::Float(value) => self.constant(InterpreterValue::Float(*value), span),
+            AstNodeType::String(value) => {
+                self.constant(InterpreterValue::String(value.clone()), span)
+            }
+            AstNodeType::Bool(value) => self.constant(InterpreterValue::Bool(*value), span),
+            AstNodeType::Ident(name, slot) => {
+                let name = self.name(name);
+                self.emit(Op::Load(name, *slot), span);
+            }
+            AstNodeType::Keyword(keyword) => {
+                self.error(InterpreterError::UnexpectedKeyword(*keyword, span), span)
+            }
+            AstNodeType::Fn {
+                name,
+                slot,
+                params,
+                body,
+            } => {
+                let function = compile_function(
+                    name.as_deref().unwrap_or(ANONYMOUS_FUNCTION_NAME).to_owned(),
+                    params
+                        .iter()
+                        .map(|param| match &param.ty {
+                            AstNodeType::Ident(name, _) => name.clone(),
+                            _ => unreachable!(),
+                        })
+                        .collect(),
+                    body,
+                );
+                self.emit(Op::Function(Rc::new(function)), span);
+                if let Some(name) = name {
+                    let name = self.name(name);
+                    self.emit(Op::Define(name, *slot), span);
+                }
+            }
+            AstNodeType::Const { name, slot, value } => {
+                self.compile(value, false);
+                let name = self.name(name);
+                self.emit(Op::DefineConstant(name, *slot), span);
+            }
+            AstNodeType::Let { name, slot, value } => {
+                self.compile(value, false);
+                let name = self.name(name);
+                self.emit(Op::Define(name, *slot), span);
+            }
+            AstNodeType::Set { name, slot, value } => {
+                self.compile(value, false);
+                let name = self.name(name);
+                self.emit(Op::Assign(name, *slot), span);
+            }
+            AstNodeType::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                self.compile(condition, false);
+                let skip_body = self.emit(Op::JumpUnless(0, "if"), condition.span);
+                self.compile(body, tail);
+                let skip_else = self.emit(Op::Jump(0), span);
+                self.height -= 1;
+                self.patch(skip_body);
+                match else_body {
+                    Some(else_body) => self.compile(else_body, tail),
+                    None => self.constant(InterpreterValue::Void, span),
+                }
+                self.patch(skip_else);
+            }
+            AstNodeType::While { condition, body } => {
+                // The loop's value is the last value of its body.
+                self.constant(InterpreterValue::Void, span);
+                let start = self.chunk.code.len();
+                self.loops.push(Loop {
+                    start,
+                    height: self.height,
+                    frames: self.frames,
+                    breaks: Vec::new(),
+                });
+                self.compile(condition, false);
+                let exit = self.emit(Op::JumpUnless(0, "while"), condition.span);
+                self.compile(body, false);
+                self.emit(Op::Nip, span);
+                self.emit(Op::Jump(start), span);
+                self.patch(exit);
+                for jump in self.loops.pop().unwrap().breaks {
+                    self.patch(jump);
+                }
+            }
+            AstNodeType::For {
+                name,
+                iterable,
+                body,
+            } => {
+                // The items, a step and the position in them stay under the
+                // loop's value until it ends.
+                self.compile_items(iterable);
+                self.constant(InterpreterValue::Void, span);
+                let start = self.chunk.code.len();
+                self.loops.push(Loop {
+                    start,
+                    height: self.height,
+                    frames: self.frames,
+                    breaks: Vec::new(),
+                });
+                let exit = self.emit(Op::Next(0), span);
+                // Each iteration binds the item in a frame of its own, as in
+                // the tree-walker.
+                self.emit(Op::PushFrame, span);
+                self.frames += 1;
+                let name = self.name(name);
+                self.emit(Op::Define(name, Slot::Local { depth: 0, index: 0 }), span);
+                self.emit(Op::Pop, span);
+                self.compile(body, false);
+                self.frames -= 1;
+                self.emit(Op::PopFrame, span);
+                self.emit(Op::Nip, span);
+                // `Next` only leaves the item when it doesn't jump, so the
+                // height is the same at the end.
+                self.emit(Op::Jump(start), span);
+                self.patch(exit);
+                for jump in self.loops.pop().unwrap().breaks {
+                    self.patch(jump);
+                }
+                for _ in 0..3 {
+                    self.emit(Op::Nip, span);
+                }
+            }
+            AstNodeType::Return(value) => {
+                match value {
+                    Some(value) => self.compile(value, self.function),
+                    None => self.constant(InterpreterValue::Void, span),
+                }
+                self.emit(Op::Return, span);
+            }
+            AstNodeType::Break => self.leave_iteration("break", span),
+            AstNodeType::Continue => self.leave_iteration("continue", span),
+            AstNodeType::Main(_) => self.error(InterpreterError::MainInInnerScope(span), span),
+            AstNodeType::Import { .. } => {
+                self.error(InterpreterError::ImportInInnerScope(span), span)
+            }
+            AstNodeType::Call {
+                name: callee,
+                slot,
+                params,
+            } => {
+                let name = self.name(callee);
+                self.emit(
+                    Op::Callee {
+                        name,
+                        slot: *slot,
+                        args: params.len(),
+                    },
+                    span,
+                );
+                // `and` and `or` evaluate their arguments lazily, which
+                // natives can't do here, so they are compiled to jumps. The
+                // name may refer to something else by the time it runs.
+                let mut skip_call = None;
+                if !params.is_empty() && matches!(callee.as_str(), "and" | "or") {
+                    let call = self.emit(Op::JumpUnlessNative(name, 0), span);
+                    self.short_circuit(params, callee == "or", span);
+                    skip_call = Some(self.emit(Op::Jump(0), span));
+                    self.patch(call);
+                }
+                for param in params {
+                    self.compile(param, false);
+                }
+                let args = Rc::new(interpreter::argument_nodes(params.len(), span));
+                if tail {
+                    self.emit(Op::TailCall(args), span);
+                } else {
+                    self.emit(Op::Call(args), span);
+                }
+                if let Some(skip_call) = skip_call {
+                    self.patch(skip_call);
+                }
+            }
+            AstNodeType::Block(nodes) => {
+                self.emit(Op::PushFrame, span);
+                self.frames += 1;
+                if nodes.is_empty() {
+                    self.constant(InterpreterValue::Void, span);
+                }
+                for (i, node) in nodes.iter().enumerate() {
+                    let last = i == nodes.len() - 1;
+                    self.compile(node, tail && last);
+                    if !last {
+                        self.emit(Op::Pop, node.span);
+                    }
+                }
+                self.frames -= 1;
+                self.emit(Op::PopFrame, span);
+            }
+            AstNodeType::Array(nodes) => {
+                for node in nodes {
+                    self.compile(node, false);
+                }
+                self.emit(Op::Array(nodes.len()), span);
+            }
+            AstNodeType::Dict(entries) => {
+                for (key, value) in entries {
+                    self.compile(key, false);
+                    self.emit(Op::Key, key.span);
+                    self.compile(value, false);
+                }
+                self.emit(Op::Dict(entries.len()), span);
+            }
+        }
+    }
+
+    /// Compiles what a `for` loop goes through. A call to the native `range`
+    /// leaves its end, step and start, so no array is made; anything else
+    /// leaves its items, no step and the position 0.
+    fn compile_items(&mut self, iterable: &AstNode) {
+        let span = iterable.span;
+        if let AstNodeType::Call {
+            name: callee,
+            slot,
+            params,
+        } = &iterable.ty
+        {
+            if callee == "range" && (1..=3).contains(&params.len()) {
+                let name = self.name(callee);
+                let args = params.len();
+                self.emit(Op::Callee { name, slot: *slot, args }, span);
+                // As with `and` and `or`, the name may refer to something
+                // else by the time it runs.
+                let call = self.emit(Op::JumpUnlessNative(name, 0), span);
+                for param in params {
+                    self.compile(param, false);
+                }
+                self.emit(Op::Range(args), span);
+                let skip_call = self.emit(Op::Jump(0), span);
+                // The callee is still on the stack when the jump is taken.
+                self.height -= 2;
+                self.patch(call);
+                for param in params {
+                    self.compile(param, false);
+                }
+                let args = Rc::new(interpreter::argument_nodes(args, span));
+                self.emit(Op::Call(args), span);
+                self.items(span);
+                self.patch(skip_call);
+                return;
+            }
+        }
+        self.compile(iterable, false);
+        self.items(span);
+    }
+
+    fn items(&mut self, span: Span) {
+        self.emit(Op::Items, span);
+        self.constant(InterpreterValue::Void, span);
+        self.constant(InterpreterValue::Int(0), span);
+    }
+
+    /// Compiles the arguments of `and` or `or`, stopping at the first one
+    /// that is `stop_at`, as the natives do.
+    fn short_circuit(&mut self, params: &[AstNode], stop_at: bool, span: Span) {
+        let keyword = if stop_at { "or" } else { "and" };
+        let mut stops = Vec::new();
+        for param in params {
+            self.compile(param, false);
+            if stop_at {
+                let next = self.emit(Op::JumpUnless(0, keyword), span);
+                self.constant(InterpreterValue::Bool(true), span);
+                stops.push(self.emit(Op::Jump(0), span));
+                self.height -= 1;
+                self.patch(next);
+            } else {
+                stops.push(self.emit(Op::JumpUnless(0, keyword), span));
+            }
+        }
+        self.constant(InterpreterValue::Bool(!stop_at), span);
+        if !stop_at {
+            let end = self.emit(Op::Jump(0), span);
+            self.height -= 1;
+            for stop in stops.drain(..) {
+                self.patch(stop);
+            }
+            self.constant(InterpreterValue::Bool(false), span);
+            self.patch(end);
+        }
+        for stop in stops {
+            self.patch(stop);
+        }
+    }
+
+    /// Compiles `break` or `continue`, which drop what the current iteration
+    /// left on the stack and leave the frames it opened.
+    fn leave_iteration(&mut self, keyword: &str, span: Span) {
+        let Some(current) = self.loops.last() else {
+            // The parser only allows these in loops.
+            let error = InterpreterError::UnexpectedControlFlow(keyword.to_owned(), span);
+            return self.error(error, span);
+        };
+        let (start, height, frames) = (current.start, current.height, current.frames);
+        let after = self.height + 1;
+        self.emit(Op::Truncate(height), span);
+        for _ in frames..self.frames {
+            self.emit(Op::PopFrame, span);
+        }
+        if keyword == "break" {
+            let jump = self.emit(Op::Jump(0), span);
+            self.loops.last_mut().unwrap().breaks.push(jump);
+        } else {
+            self.emit(Op::Jump(start), span);
+        }
+        self.height = after;
+    }
+}
@@ -0,0 +1,350 @@
+use anyhow::Result;
+use std::{collections::BTreeSet, fmt, io, rc::Rc};
+
+use crate::ast::AstNode;
+use crate::diagnostic;
+use crate::interpreter::{Exit, InterpreterScope, InterpreterValue, Output};
+use crate::module::SourceMap;
+
+const HELP: &str = "\
+commands:
+  s, step          run to the next statement
+  n, next          run to the next statement, stepping over calls
+  c, continue      run to the next breakpoint
+  b, break LINE    pause at each statement on LINE
+  d, delete LINE   remove the breakpoint on LINE
+  v, vars          show the variables in scope, innermost first
+  p, print NAME    show the variable NAME
+  q, quit          stop the program
+an empty line repeats the last command";
+
+/// Reads a command into the string, like `Stdin::read_line`.
+type ReadLine = dyn FnMut(&mut String) -> io::Result<usize>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Run until a breakpoint.
+    Continue,
+    /// Pause at the next statement.
+    Step,
+    /// Pause at the next statement that isn't in a function called from the
+    /// one paused in, which was this many calls deep.
+    Next(usize),
+}
+
+/// Pauses a program run by the tree-walker before statements on the lines
+/// with breakpoints, and lets the user step through it and look at its
+/// variables. Without breakpoints it pauses at the first statement.
+pub struct Debugger {
+    sources: Rc<SourceMap>,
+    /// The file the breakpoints' lines are in.
+    file: usize,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    /// How many function calls deep the program is.
+    depth: usize,
+    last_command: String,
+    read_line: Box<ReadLine>,
+    output: Output,
+}
+
+impl Debugger {
+    /// A debugger for the program in `file`, which reads commands with
+    /// `read_line` and writes to `output`.
+    pub fn new(
+        sources: Rc<SourceMap>,
+        file: usize,
+        breakpoints: impl IntoIterator<Item = usize>,
+        read_line: impl FnMut(&mut String) -> io::Result<usize> + 'static,
+        output: Output,
+    ) -> Self {
+        let breakpoints = breakpoints.into_iter().collect::<BTreeSet<_>>();
+        Self {
+            sources,
+            file,
+            mode: if breakpoints.is_empty() { Mode::Step } else { Mode::Continue },
+            breakpoints,
+            depth: 0,
+            last_command: String::new(),
+            read_line: Box::new(read_line),
+            output,
+        }
+    }
+
+    pub(crate) fn enter_call(&mut self) {
+        self.depth += 1;
+    }
+
+    pub(crate) fn leave_call(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Called before each statement is run. If the program should pause
+    /// there, shows the statement and runs commands until one resumes it.
+    pub(crate) fn before(&mut self, scope: &InterpreterScope, node: &AstNode) -> Result<()> {
+        let pause = match self.mode {
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next(depth) => self.depth <= depth,
+        };
+        let span = node.span;
+        let breakpoint = span.file == self.file && self.breakpoints.contains(&span.line);
+        if !pause && !breakpoint {
+            return Ok(());
+        }
+
+        let snippet = diagnostic::render_snippet(span, &self.sources);
+        self.write(format_args!("paused\n{}", snippet))?;
+        loop {
+            self.write(format_args!("(debug) "))?;
+            let mut line = String::new();
+            if (self.read_line)(&mut line)? == 0 {
+                // Without more commands, let the program finish.
+                self.write(format_args!("\n"))?;
+                self.breakpoints.clear();
+                self.mode = Mode::Continue;
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_owned(),
+            };
+            self.last_command = line.clone();
+            if self.command(scope, &line)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a command, and returns whether it resumes the program.
+    fn command(&mut self, scope: &InterpreterScope, line: &str) -> Result<bool> {
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "s" | "step" => self.mode = Mode::Step,
+            "n" | "next" => self.mode = Mode::Next(self.depth),
+            "c" | "continue" => self.mode = Mode::Continue,
+            "b" | "break" => match arg.parse() {
+                Ok(line) => {
+                    self.breakpoints.insert(line);
+                    self.write(format_args!("breakpoint on line {}\n", line))?;
+                    return Ok(false);
+                }
+                Err(_) => return self.usage("break LINE"),
+            },
+            "d" | "delete" => match arg.parse() {
+                Ok(line) if self.breakpoints.remove(&line) => {
+                    self.write(format_args!("removed the breakpoint on line {}\n", line))?;
+                    return Ok(false);
+                }
+                Ok(line) => {
+                    self.write(format_args!("there is no breakpoint on line {}\n", line))?;
+                    return Ok(false);
+                }
+                Err(_) => return self.usage("delete LINE"),
+            },
+            "v" | "vars" => {
+                self.show_variables(scope)?;
+                return Ok(false);
+            }
+            "p" | "print" if !arg.is_empty() => {
+                let value = variables(scope)
+                    .into_iter()
+                    .flatten()
+                    .find(|(name, _)| name == arg);
+                match value {
+                    Some((name, value)) => {
+                        self.write(format_args!("{} = {}\n", name, value.to_formatted_string()))?
+                    }
+                    None => self.write(format_args!("`{}` is not in scope\n", arg))?,
+                }
+                return Ok(false);
+            }
+            "p" | "print" => return self.usage("print NAME"),
+            "q" | "quit" => return Err(Exit(0).into()),
+            "h" | "help" => {
+                self.write(format_args!("{}\n", HELP))?;
+                return Ok(false);
+            }
+            _ => {
+                self.write(format_args!("unknown command `{}`, try `help`\n", command))?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Lists the variables of each frame in scope, innermost first. Natives
+    /// are left out of the top frame.
+    fn show_variables(&mut self, scope: &InterpreterScope) -> Result<()> {
+        let frames = variables(scope);
+        let top = frames.len() - 1;
+        for (i, frame) in frames.into_iter().enumerate() {
+            let frame = frame
+                .into_iter()
+                .filter(|(_, value)| {
+                    !matches!(value.as_ref(), InterpreterValue::NativeFunction { .. })
+                })
+                .collect::<Vec<_>>();
+            if frame.is_empty() {
+                continue;
+            }
+            if i == top {
+                self.write(format_args!("globals:\n"))?;
+            } else {
+                self.write(format_args!("frame {}:\n", i))?;
+            }
+            for (name, value) in frame {
+                self.write(format_args!("  {} = {}\n", name, value.to_formatted_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn usage(&mut self, usage: &str) -> Result<bool> {
+        self.write(format_args!("usage: {}\n", usage))?;
+        Ok(false)
+    }
+
+    fn write(&mut self, text: fmt::Arguments) -> Result<()> {
+        let mut output = self.output.0.borrow_mut();
+        output.write_fmt(text)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Debugger")
+            .field("breakpoints", &self.breakpoints)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The variables of each frame of `scope`, innermost first.
+fn variables(scope: &InterpreterScope) -> Vec<Vec<(String, Rc<InterpreterValue>)>> {
+    let mut frames = Vec::new();
+    let mut env = Some(&scope.env);
+    while let Some(frame) = env {
+        frames.push(frame.variables());
+        env = frame.parent();
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_fns::default_native_functions;
+    use crate::interpreter::{interpret, Context};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    const PROGRAM: &str = "\
+fn square(x) {
+    let y (* x x)
+    y
+}
+
+main {
+    let a 3
+    let b (square a)
+    (print b)
+    [a b]
+}
+";
+
+    /// Runs `PROGRAM` under the debugger with `commands` as input, and
+    /// returns what the debugger and the program wrote, along with the
+    /// result.
+    fn debug(breakpoints: &[usize], commands: &[&str]) -> (String, Result<Rc<InterpreterValue>>) {
+        let sources = Rc::new(SourceMap::default());
+        let file = sources.add("debug.thing", PROGRAM.to_owned());
+        let mut commands = commands.iter().map(|c| format!("{}\n", c)).collect::<VecDeque<_>>();
+        let read_line = move |line: &mut String| {
+            let command = commands.pop_front().unwrap_or_default();
+            line.push_str(&command);
+            Ok(command.len())
+        };
+        let (output, written) = Output::buffer();
+        let debugger = Debugger::new(
+            sources.clone(),
+            file,
+            breakpoints.iter().copied(),
+            read_line,
+            output.clone(),
+        );
+        let context = Context {
+            output,
+            debugger: Some(Rc::new(RefCell::new(debugger))),
+            ..Default::default()
+        };
+        let ast = Parser::try_new(Lexer::new(PROGRAM).with_file(file)).unwrap().parse().unwrap();
+        let result = interpret(ast, default_native_functions(), sources, context);
+        let written = String::from_utf8(written.borrow().clone()).unwrap();
+        (written, result)
+    }
+
+    /// The lines the program paused at.
+    fn pauses(written: &str) -> Vec<usize> {
+        written
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix("--> debug.thing:"))
+            .map(|at| at.split(':').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let (written, result) = debug(&[], &["s", "s", "", "", "n", "n"]);
+        assert_eq!(result.unwrap().to_string(), "[3, 9]");
+        assert_eq!(pauses(&written), [7, 8, 2, 3, 9, 10]);
+
+        let (written, _) = debug(&[], &["n", "n", "n"]);
+        assert_eq!(pauses(&written), [7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (written, _) = debug(&[3], &["b 10", "d 10", "c"]);
+        assert_eq!(pauses(&written), [3]);
+
+        let (written, _) = debug(&[3], &["b 10", "c", "c"]);
+        assert_eq!(pauses(&written), [3, 10]);
+        assert!(written.contains("breakpoint on line 10"));
+    }
+
+    #[test]
+    fn test_variables() {
+        let (written, _) = debug(&[3], &["vars", "p x", "p a", "c"]);
+        let expected = "\
+(debug) frame 0:
+  y = 9
+frame 1:
+  x = 3
+globals:
+  square = Function { name: square, params: [\"x\"] }
+(debug) x = 3
+(debug) `a` is not in scope
+";
+        assert!(written.contains(expected), "{}", written);
+    }
+
+    #[test]
+    fn test_quit_and_end_of_input() {
+        let (written, result) = debug(&[], &["q"]);
+        assert_eq!(Exit::code(&result.unwrap_err()), Some(0));
+        assert!(!written.contains("9\n"));
+
+        let (written, result) = debug(&[], &[]);
+        assert!(result.is_ok());
+        assert_eq!(pauses(&written), [7]);
+        assert!(written.ends_with("9\n"));
+    }
+}
@@ -1,55 +1,121 @@
-use std::{collections::HashMap, rc::Rc};
+use anyhow::Result;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Write},
+    rc::Rc,
+};
 
-use crate::interpreter::{InterpreterError, InterpreterValue, NativeFn};
+use crate::ast::AstNode;
+use crate::diagnostic::Span;
+use crate::interpreter::{
+    DictKey, Exit, InterpreterError, InterpreterScope, InterpreterValue, NativeFn,
+};
+
+/// Applies an arithmetic operator to two numbers, or returns `None` if one of
+/// them isn't a number. An int mixed with a float is promoted to a float. Int
+/// results are checked, so overflow is an error instead of wrapping, and
+/// dividing by zero is an error for ints and floats alike.
+fn arithmetic(
+    a: &InterpreterValue,
+    b: &InterpreterValue,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+    op_str: &str,
+    span: Span,
+) -> Option<Result<Rc<InterpreterValue>>> {
+    let (a, b) = match (a, b) {
+        (InterpreterValue::Int(a), InterpreterValue::Int(b)) => {
+            return Some(match int_op(*a, *b) {
+                Some(value) => Ok(Rc::new(InterpreterValue::Int(value))),
+                // Adding, subtracting or multiplying by 0 can't fail.
+                None if *b == 0 => Err(InterpreterError::DivisionByZero(span).into()),
+                None => Err(InterpreterError::IntegerOverflow(op_str.to_owned(), span).into()),
+            });
+        }
+        (InterpreterValue::Int(a), InterpreterValue::Float(b)) => (*a as f64, *b),
+        (InterpreterValue::Float(a), InterpreterValue::Int(b)) => (*a, *b as f64),
+        (InterpreterValue::Float(a), InterpreterValue::Float(b)) => (*a, *b),
+        _ => return None,
+    };
+    if op_str == "/" && b == 0.0 {
+        return Some(Err(InterpreterError::DivisionByZero(span).into()));
+    }
+    Some(Ok(Rc::new(InterpreterValue::Float(float_op(a, b)))))
+}
 
 macro_rules! create_function {
-    ($op:ident, $op2:ident, $op_str:expr) => {
-        |scope, params| {
+    ($checked:ident, $op:ident, $op2:ident, $op_str:expr) => {
+        |scope, params, span| {
             if params.len() == 0 {
-                return Err(InterpreterError::InvalidFunctionCall($op_str.to_owned()).into());
+                return Err(InterpreterError::InvalidFunctionCall($op_str.to_owned(), span).into());
             }
             let params = scope.evaluate_each(params)?;
 
             let mut iter = params.into_iter();
             let first = iter.next().unwrap();
             let mut i = 0;
-            let accum = iter.try_fold(first.clone(), |accum, next| {
+            iter.try_fold(first.clone(), |accum, next| {
                 i += 1;
-                match (accum.as_ref(), next.as_ref()) {
-                    (InterpreterValue::Int(a), InterpreterValue::Int(b)) => {
-                        return Ok(Rc::new(InterpreterValue::Int((std::ops::$op::$op2)(a, b))));
-                    }
-                    (InterpreterValue::Float(a), InterpreterValue::Float(b)) => {
-                        return Ok(Rc::new(InterpreterValue::Float((std::ops::$op::$op2)(
-                            a, b,
-                        ))));
-                    }
-                    (_, b) => {
-                        return Err(b.get_type().to_string());
-                    }
-                };
-            });
-            match accum {
-                Err(e) => {
-                    return Err(InterpreterError::InvalidTypeArgNative(
-                        e,
+                let result = arithmetic(
+                    &accum,
+                    &next,
+                    i64::$checked,
+                    std::ops::$op::$op2,
+                    $op_str,
+                    span,
+                );
+                result.unwrap_or_else(|| {
+                    Err(InterpreterError::InvalidTypeArgNative(
+                        next.get_type().to_string(),
                         i,
                         $op_str.to_owned(),
                         first.get_type().to_string(),
+                        span,
+                    )
+                    .into())
+                })
+            })
+        }
+    };
+}
+
+macro_rules! create_comparison {
+    ($op:tt, $op_str:expr) => {
+        |scope, params, span| {
+            if params.len() != 2 {
+                return Err(InterpreterError::InvalidFunctionCall($op_str.to_owned(), span).into());
+            }
+            let params = scope.evaluate_each(params)?;
+
+            let result = match (params[0].as_ref(), params[1].as_ref()) {
+                (InterpreterValue::Int(a), InterpreterValue::Int(b)) => a $op b,
+                (InterpreterValue::Int(a), InterpreterValue::Float(b)) => (*a as f64) $op *b,
+                (InterpreterValue::Float(a), InterpreterValue::Int(b)) => *a $op (*b as f64),
+                (InterpreterValue::Float(a), InterpreterValue::Float(b)) => a $op b,
+                (InterpreterValue::String(a), InterpreterValue::String(b)) => a $op b,
+                (a, b) => {
+                    return Err(InterpreterError::InvalidType2Native(
+                        a.get_type().to_string(),
+                        b.get_type().to_string(),
+                        $op_str.to_owned(),
+                        span,
                     )
                     .into());
                 }
-                Ok(v) => return Ok(v),
-            }
+            };
+            Ok(Rc::new(InterpreterValue::Bool(result)))
         }
     };
 }
 
 macro_rules! create_conversion_function {
     ($fn_name:expr, $return_type:ident, $int_conversion:expr, $float_conversion:expr, $string_conversion:expr, $bool_conversion:expr) => {
-        |scope, params| {
+        |scope, params, span| {
             if params.len() != 1 {
-                return Err(InterpreterError::InvalidFunctionCall($fn_name.to_owned()).into());
+                return Err(InterpreterError::InvalidFunctionCall($fn_name.to_owned(), span).into());
             }
             let params = scope.evaluate_each(params)?;
 
@@ -61,15 +127,19 @@ macro_rules! create_conversion_function {
                 InterpreterValue::Float(f) => Ok(Rc::new(InterpreterValue::$return_type(
                     $float_conversion(*f),
                 ))),
-                InterpreterValue::String(s) => Ok(Rc::new(InterpreterValue::$return_type(
-                    $string_conversion(s),
-                ))),
+                InterpreterValue::String(s) => {
+                    let converted = $string_conversion(s).map_err(|_| {
+                        InterpreterError::InvalidNumber(s.clone(), $fn_name.to_owned(), span)
+                    })?;
+                    Ok(Rc::new(InterpreterValue::$return_type(converted)))
+                }
                 InterpreterValue::Bool(b) => Ok(Rc::new(InterpreterValue::$return_type(
                     $bool_conversion(*b),
                 ))),
                 _ => Err(InterpreterError::InvalidType1Native(
                     param.get_type().to_string(),
                     $fn_name.to_owned(),
+                    span,
                 )
                 .into()),
             }
@@ -77,139 +147,114 @@ macro_rules! create_conversion_function {
     };
 }
 
+/// The natives defined here don't need any state, so they are plain
+/// functions until they are handed to the interpreter.
+type Builtin = fn(&mut InterpreterScope, &Vec<AstNode>, Span) -> Result<Rc<InterpreterValue>>;
+
 pub fn default_native_functions() -> HashMap<String, NativeFn> {
-    let mut functions: HashMap<String, NativeFn> = HashMap::new();
+    let mut functions: HashMap<String, Builtin> = HashMap::new();
 
-    functions.insert("print".to_string(), |scope, params| {
+    functions.insert("print".to_string(), |scope, params, span| {
         let params = scope.evaluate_each(params)?;
         for param in params {
-            println!("{}", param.to_string());
+            let printed = scope.context.output.print(format_args!("{}", param));
+            printed.map_err(|e| io_failed(e, "print", span))?;
         }
         Ok(Rc::new(InterpreterValue::Void))
     });
 
-    functions.insert("dbg".to_string(), |scope, params| {
+    functions.insert("dbg".to_string(), |scope, params, span| {
         if params.len() != 1 {
-            return Err(InterpreterError::InvalidFunctionCall("dbg".to_owned()).into());
+            return Err(InterpreterError::InvalidFunctionCall("dbg".to_owned(), span).into());
         }
         let params = scope.evaluate_each(params)?;
-        println!("{:#?}", params[0]);
+        let printed = scope.context.output.print(format_args!("{:#?}", params[0]));
+        printed.map_err(|e| io_failed(e, "dbg", span))?;
         Ok(params[0].clone())
     });
 
-    functions.insert("==".to_string(), |scope, params| {
+    functions.insert("==".to_string(), |scope, params, span| {
         if params.len() != 2 {
-            return Err(InterpreterError::InvalidFunctionCall("==".to_owned()).into());
+            return Err(InterpreterError::InvalidFunctionCall("==".to_owned(), span).into());
         }
         let params = scope.evaluate_each(params)?;
-
-        let mut iter = params.into_iter();
-        let first = iter.next().unwrap();
-        for param in iter {
-            match (first.as_ref(), param.as_ref()) {
-                (InterpreterValue::Int(a), InterpreterValue::Int(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a == b)));
-                }
-                (InterpreterValue::Float(a), InterpreterValue::Float(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a == b)));
-                }
-                (InterpreterValue::String(a), InterpreterValue::String(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a == b)));
-                }
-                (InterpreterValue::Bool(a), InterpreterValue::Bool(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a == b)));
-                }
-                (a, b) => {
-                    return Err(InterpreterError::InvalidType2Native(
-                        a.get_type().to_string(),
-                        b.get_type().to_string(),
-                        "==".to_owned(),
-                    )
-                    .into());
-                }
-            }
-        }
-        Ok(Rc::new(InterpreterValue::Int(1)))
+        let equal = expect_comparable(&params[0], &params[1], "==", span)?;
+        Ok(Rc::new(InterpreterValue::Bool(equal)))
     });
 
-    functions.insert("!=".to_string(), |scope, params| {
+    functions.insert("!=".to_string(), |scope, params, span| {
         if params.len() != 2 {
-            return Err(InterpreterError::InvalidFunctionCall("!=".to_owned()).into());
+            return Err(InterpreterError::InvalidFunctionCall("!=".to_owned(), span).into());
         }
         let params = scope.evaluate_each(params)?;
+        let equal = expect_comparable(&params[0], &params[1], "!=", span)?;
+        Ok(Rc::new(InterpreterValue::Bool(!equal)))
+    });
 
-        let mut iter = params.into_iter();
-        let first = iter.next().unwrap();
-        for param in iter {
-            match (first.as_ref(), param.as_ref()) {
-                (InterpreterValue::Int(a), InterpreterValue::Int(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a != b)));
-                }
-                (InterpreterValue::Float(a), InterpreterValue::Float(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a != b)));
-                }
-                (InterpreterValue::String(a), InterpreterValue::String(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a != b)));
-                }
-                (InterpreterValue::Bool(a), InterpreterValue::Bool(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Bool(a != b)));
-                }
-                (a, b) => {
-                    return Err(InterpreterError::InvalidType2Native(
-                        a.get_type().to_string(),
-                        b.get_type().to_string(),
-                        "!=".to_owned(),
-                    )
-                    .into());
-                }
-            }
+    functions.insert("<".to_string(), create_comparison!(<, "<"));
+    functions.insert("<=".to_string(), create_comparison!(<=, "<="));
+    functions.insert(">".to_string(), create_comparison!(>, ">"));
+    functions.insert(">=".to_string(), create_comparison!(>=, ">="));
+
+    // `and` and `or` only evaluate their arguments until the result is known.
+    // The VM's compiler has to do the same for calls to them.
+    functions.insert("and".to_string(), |scope, params, span| {
+        short_circuit(scope, params, false, "and", span)
+    });
+
+    functions.insert("or".to_string(), |scope, params, span| {
+        short_circuit(scope, params, true, "or", span)
+    });
+
+    functions.insert("not".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("not".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        match params[0].as_ref() {
+            InterpreterValue::Bool(b) => Ok(Rc::new(InterpreterValue::Bool(!b))),
+            value => Err(InterpreterError::InvalidType1Native(
+                value.get_type().to_string(),
+                "not".to_owned(),
+                span,
+            )
+            .into()),
         }
-        Ok(Rc::new(InterpreterValue::Int(1)))
     });
 
-    functions.insert("+".to_string(), |scope, params| {
-        if params.len() == 0 {
-            return Err(InterpreterError::InvalidFunctionCall("+".to_owned()).into());
+    functions.insert("+".to_string(), |scope, params, span| {
+        if params.is_empty() {
+            return Err(InterpreterError::InvalidFunctionCall("+".to_owned(), span).into());
         }
         let params = scope.evaluate_each(params)?;
 
         let mut iter = params.into_iter();
         let first = iter.next().unwrap();
         let mut i = 0;
-        let accum = iter.try_fold(first.clone(), |accum, next| {
+        iter.try_fold(first.clone(), |accum, next| {
             i += 1;
-            match (accum.as_ref(), next.as_ref()) {
-                (InterpreterValue::Int(a), InterpreterValue::Int(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Int((std::ops::Add::add)(a, b))));
-                }
-                (InterpreterValue::Float(a), InterpreterValue::Float(b)) => {
-                    return Ok(Rc::new(InterpreterValue::Float((std::ops::Add::add)(a, b))));
-                }
-                (InterpreterValue::String(a), InterpreterValue::String(b)) => {
-                    return Ok(Rc::new(InterpreterValue::String(a.to_owned() + b)));
-                }
-                (_, b) => {
-                    return Err(b.get_type().to_string());
-                }
-            };
-        });
-        match accum {
-            Err(e) => {
-                return Err(InterpreterError::InvalidTypeArgNative(
-                    e,
+            if let (InterpreterValue::String(a), InterpreterValue::String(b)) =
+                (accum.as_ref(), next.as_ref())
+            {
+                return Ok(Rc::new(InterpreterValue::String(a.to_owned() + b)));
+            }
+            let result = arithmetic(&accum, &next, i64::checked_add, std::ops::Add::add, "+", span);
+            result.unwrap_or_else(|| {
+                Err(InterpreterError::InvalidTypeArgNative(
+                    next.get_type().to_string(),
                     i,
                     "+".to_owned(),
                     first.get_type().to_string(),
+                    span,
                 )
-                .into());
-            }
-            Ok(v) => return Ok(v),
-        }
+                .into())
+            })
+        })
     });
 
-    functions.insert("-".to_string(), create_function!(Sub, sub, "-"));
-    functions.insert("*".to_string(), create_function!(Mul, mul, "*"));
-    functions.insert("/".to_string(), create_function!(Div, div, "/"));
+    functions.insert("-".to_string(), create_function!(checked_sub, Sub, sub, "-"));
+    functions.insert("*".to_string(), create_function!(checked_mul, Mul, mul, "*"));
+    functions.insert("/".to_string(), create_function!(checked_div, Div, div, "/"));
 
     functions.insert(
         "int".to_string(),
@@ -218,7 +263,7 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
             Int,
             |i| i,
             |f| f as i64,
-            |s: &String| s.parse::<i64>().unwrap(),
+            |s: &String| s.parse::<i64>(),
             |b| b as i64
         ),
     );
@@ -229,23 +274,31 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
             "float",
             Float,
             |i| i as f64,
-            |f| f as f64,
-            |s: &String| s.parse::<f64>().unwrap(),
+            |f| f,
+            |s: &String| s.parse::<f64>(),
             |b| b as i64 as f64
         ),
     );
 
-    functions.insert(
-        "string".to_string(),
-        create_conversion_function!(
-            "string",
-            String,
-            |i: i64| i.to_string(),
-            |f: f64| f.to_string(),
-            |s: &String| s.to_string(),
-            |b: bool| b.to_string()
-        ),
-    );
+    functions.insert("string".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("string".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+
+        match params[0].as_ref() {
+            InterpreterValue::Void
+            | InterpreterValue::Function { .. }
+            | InterpreterValue::Compiled { .. }
+            | InterpreterValue::NativeFunction { .. } => Err(InterpreterError::InvalidType1Native(
+                params[0].get_type().to_string(),
+                "string".to_owned(),
+                span,
+            )
+            .into()),
+            value => Ok(Rc::new(InterpreterValue::String(value.to_string()))),
+        }
+    });
 
     functions.insert(
         "bool".to_string(),
@@ -254,14 +307,14 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
             Bool,
             |i| i != 0,
             |f| f != 0.0,
-            |s: &String| s.parse().unwrap_or_default(),
+            |s: &String| Ok::<_, ()>(s.parse::<bool>().unwrap_or_default()),
             |b| b
         ),
     );
 
-    functions.insert("get".to_string(), |scope, params| {
+    functions.insert("get".to_string(), |scope, params, span| {
         if params.len() < 2 {
-            return Err(InterpreterError::InvalidFunctionCall("get".to_owned()).into());
+            return Err(InterpreterError::InvalidFunctionCall("get".to_owned(), span).into());
         }
         let params = scope.evaluate_each(params)?;
         let mut iter = params.into_iter();
@@ -273,7 +326,11 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
             match accum.as_ref() {
                 InterpreterValue::Array(a) => match param.as_ref() {
                     InterpreterValue::Int(i) => {
-                        accum = a[*i as usize].clone();
+                        let value = {
+                            let a = a.borrow();
+                            a[index(*i, a.len(), span)?].clone()
+                        };
+                        accum = value;
                     }
                     _ => {
                         return Err(InterpreterError::InvalidTypeArgNative(
@@ -281,15 +338,15 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
                             i,
                             "get".to_owned(),
                             "int".to_owned(),
+                            span,
                         )
                         .into());
                     }
                 },
                 InterpreterValue::String(s) => match param.as_ref() {
                     InterpreterValue::Int(i) => {
-                        accum = Rc::new(InterpreterValue::String(
-                            s.chars().nth(*i as usize).unwrap().to_string(),
-                        ));
+                        let c = s.chars().nth(index(*i, s.chars().count(), span)?).unwrap();
+                        accum = Rc::new(InterpreterValue::String(c.to_string()));
                     }
                     _ => {
                         return Err(InterpreterError::InvalidTypeArgNative(
@@ -297,6 +354,7 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
                             i,
                             "get".to_owned(),
                             "int".to_owned(),
+                            span,
                         )
                         .into());
                     }
@@ -307,6 +365,7 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
                         i,
                         "get".to_owned(),
                         "array".to_owned(),
+                        span,
                     )
                     .into());
                 }
@@ -316,5 +375,958 @@ pub fn default_native_functions() -> HashMap<String, NativeFn> {
         Ok(accum)
     });
 
+    functions.insert("dict_get".to_string(), |scope, params, span| {
+        if params.len() != 2 && params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("dict_get".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let dict = expect_dict(&params[0], 0, "dict_get", span)?;
+        let key = DictKey::new(&params[1], span)?;
+
+        match (dict.get(&key), params.get(2)) {
+            (Some(value), _) => Ok(value.clone()),
+            (None, Some(default)) => Ok(default.clone()),
+            (None, None) => Err(InterpreterError::KeyNotFound(key.to_string(), span).into()),
+        }
+    });
+
+    functions.insert("dict_set".to_string(), |scope, params, span| {
+        if params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("dict_set".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let mut dict = expect_dict(&params[0], 0, "dict_set", span)?.clone();
+        dict.insert(DictKey::new(&params[1], span)?, params[2].clone());
+        Ok(Rc::new(InterpreterValue::Dict(dict)))
+    });
+
+    functions.insert("remove".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("remove".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let mut dict = expect_dict(&params[0], 0, "remove", span)?.clone();
+        dict.remove(&DictKey::new(&params[1], span)?);
+        Ok(Rc::new(InterpreterValue::Dict(dict)))
+    });
+
+    functions.insert("has_key".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("has_key".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let dict = expect_dict(&params[0], 0, "has_key", span)?;
+        let key = DictKey::new(&params[1], span)?;
+        Ok(Rc::new(InterpreterValue::Bool(dict.contains_key(&key))))
+    });
+
+    functions.insert("keys".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("keys".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let dict = expect_dict(&params[0], 0, "keys", span)?;
+        use_fuel(scope, dict.len(), span)?;
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(
+            dict.keys().map(|k| Rc::new(k.into())).collect(),
+        ))))
+    });
+
+    functions.insert("values".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("values".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let dict = expect_dict(&params[0], 0, "values", span)?;
+        use_fuel(scope, dict.len(), span)?;
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(
+            dict.values().cloned().collect(),
+        ))))
+    });
+
+    functions.insert("len".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("len".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+
+        let len = match params[0].as_ref() {
+            InterpreterValue::Array(a) => a.borrow().len(),
+            InterpreterValue::Dict(d) => d.len(),
+            InterpreterValue::String(s) => s.chars().count(),
+            value => {
+                return Err(InterpreterError::InvalidType1Native(
+                    value.get_type().to_string(),
+                    "len".to_owned(),
+                    span,
+                )
+                .into())
+            }
+        };
+        Ok(Rc::new(InterpreterValue::Int(len as i64)))
+    });
+
+    functions.insert("push".to_string(), |scope, params, span| {
+        if params.len() < 2 {
+            return Err(InterpreterError::InvalidFunctionCall("push".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "push", span)?;
+        array.borrow_mut().extend(params[1..].iter().cloned());
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    functions.insert("pop".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("pop".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "pop", span)?;
+        let value = array.borrow_mut().pop();
+        value.ok_or_else(|| InterpreterError::IndexOutOfRange(-1, 0, span).into())
+    });
+
+    functions.insert("set_at".to_string(), |scope, params, span| {
+        if params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("set_at".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "set_at", span)?;
+        let i = expect_int(&params[1], 1, "set_at", span)?;
+
+        let mut array = array.borrow_mut();
+        let i = index(i, array.len(), span)?;
+        array[i] = params[2].clone();
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    functions.insert("insert_at".to_string(), |scope, params, span| {
+        if params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("insert_at".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "insert_at", span)?;
+        let i = expect_int(&params[1], 1, "insert_at", span)?;
+
+        let mut array = array.borrow_mut();
+        // Inserting at the end is allowed.
+        let i = match i {
+            i if i == array.len() as i64 => array.len(),
+            i => index(i, array.len(), span)?,
+        };
+        array.insert(i, params[2].clone());
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    functions.insert("remove_at".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("remove_at".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "remove_at", span)?;
+        let i = expect_int(&params[1], 1, "remove_at", span)?;
+
+        let mut array = array.borrow_mut();
+        let i = index(i, array.len(), span)?;
+        Ok(array.remove(i))
+    });
+
+    functions.insert("slice".to_string(), |scope, params, span| {
+        if params.len() != 2 && params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("slice".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "slice", span)?.borrow();
+        let start = expect_int(&params[1], 1, "slice", span)?;
+        let end = match params.get(2) {
+            Some(end) => expect_int(end, 2, "slice", span)?,
+            None => array.len() as i64,
+        };
+
+        // Both ends may be one past the last element. A start after the end
+        // gives an empty array.
+        let start = index(start, array.len() + 1, span)?;
+        let end = index(end, array.len() + 1, span)?;
+        use_fuel(scope, end.saturating_sub(start), span)?;
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(
+            array[start.min(end)..end].to_vec(),
+        ))))
+    });
+
+    functions.insert("concat".to_string(), |scope, params, span| {
+        let params = scope.evaluate_each(params)?;
+        let mut result = Vec::new();
+        for (i, param) in params.iter().enumerate() {
+            let array = expect_array(param, i, "concat", span)?.borrow();
+            use_fuel(scope, array.len(), span)?;
+            result.extend(array.iter().cloned());
+        }
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(result))))
+    });
+
+    insert_collection_functions(&mut functions);
+    insert_string_functions(&mut functions);
+    insert_io_functions(&mut functions);
+
     functions
+        .into_iter()
+        .map(|(name, builtin)| (name, NativeFn::new(builtin)))
+        .collect()
+}
+
+/// Functions over arrays that take a callback, which can be a user function
+/// or a native. Callbacks see a copy of the array, so changing the array
+/// inside one doesn't change what is being iterated over.
+fn insert_collection_functions(functions: &mut HashMap<String, Builtin>) {
+    functions.insert("map".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("map".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "map", span)?.borrow().clone();
+        expect_function(&params[1], 1, "map", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        let mut result = Vec::with_capacity(array.len());
+        for value in array {
+            result.push(scope.call(&params[1], vec![value], span)?);
+        }
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(result))))
+    });
+
+    functions.insert("filter".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("filter".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "filter", span)?.borrow().clone();
+        expect_function(&params[1], 1, "filter", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        let mut result = Vec::new();
+        for value in array {
+            if call_predicate(scope, &params[1], value.clone(), "filter", span)? {
+                result.push(value);
+            }
+        }
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(result))))
+    });
+
+    functions.insert("reduce".to_string(), |scope, params, span| {
+        if params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("reduce".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "reduce", span)?.borrow().clone();
+        expect_function(&params[1], 1, "reduce", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        let mut accum = params[2].clone();
+        for value in array {
+            accum = scope.call(&params[1], vec![accum, value], span)?;
+        }
+        Ok(accum)
+    });
+
+    functions.insert("for_each".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("for_each".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "for_each", span)?.borrow().clone();
+        expect_function(&params[1], 1, "for_each", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        for value in array {
+            scope.call(&params[1], vec![value], span)?;
+        }
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    functions.insert("any".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("any".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "any", span)?.borrow().clone();
+        expect_function(&params[1], 1, "any", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        for value in array {
+            if call_predicate(scope, &params[1], value, "any", span)? {
+                return Ok(Rc::new(InterpreterValue::Bool(true)));
+            }
+        }
+        Ok(Rc::new(InterpreterValue::Bool(false)))
+    });
+
+    functions.insert("all".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("all".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "all", span)?.borrow().clone();
+        expect_function(&params[1], 1, "all", span)?;
+        use_fuel(scope, array.len(), span)?;
+
+        for value in array {
+            if !call_predicate(scope, &params[1], value, "all", span)? {
+                return Ok(Rc::new(InterpreterValue::Bool(false)));
+            }
+        }
+        Ok(Rc::new(InterpreterValue::Bool(true)))
+    });
+
+    // Sorts the array in place and returns it. The callback compares two
+    // elements and returns a negative int, 0 or a positive int.
+    functions.insert("sort_by".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("sort_by".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "sort_by", span)?;
+        expect_function(&params[1], 1, "sort_by", span)?;
+
+        let values = array.borrow().clone();
+        use_fuel(scope, values.len(), span)?;
+        let sorted = merge_sort(values, &mut |a, b| {
+            let result = scope.call(&params[1], vec![a.clone(), b.clone()], span)?;
+            match result.as_ref() {
+                InterpreterValue::Int(i) => Ok(i.cmp(&0)),
+                _ => Err(InterpreterError::InvalidType1Native(
+                    result.get_type().to_string(),
+                    "sort_by".to_owned(),
+                    span,
+                )
+                .into()),
+            }
+        })?;
+        *array.borrow_mut() = sorted;
+        Ok(params[0].clone())
+    });
+
+    // `(range end)`, `(range start end)` or `(range start end step)`. The end
+    // is not included.
+    functions.insert("range".to_string(), |scope, params, span| {
+        if params.is_empty() || params.len() > 3 {
+            return Err(InterpreterError::InvalidFunctionCall("range".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let range = Range::new(&params, span)?;
+        // The fuel is used up front, so a range too long for it fails
+        // without being made.
+        use_fuel(scope, range.len(), span)?;
+        let result = range.map(|n| Rc::new(InterpreterValue::Int(n))).collect();
+        Ok(Rc::new(InterpreterValue::Array(RefCell::new(result))))
+    });
+}
+
+/// The ints `range` gives, made one at a time. `for` loops over a call to
+/// `range` go through them without making the array.
+pub(crate) struct Range {
+    /// `None` once stepping has gone past the largest or smallest int.
+    pub(crate) next: Option<i64>,
+    pub(crate) end: i64,
+    pub(crate) step: i64,
+}
+
+impl Range {
+    /// Reads the one to three arguments of `range`.
+    pub(crate) fn new(params: &[Rc<InterpreterValue>], span: Span) -> Result<Self> {
+        let mut ints = Vec::with_capacity(params.len());
+        for (i, param) in params.iter().enumerate() {
+            ints.push(expect_int(param, i, "range", span)?);
+        }
+        let (start, end, step) = match ints[..] {
+            [end] => (0, end, 1),
+            [start, end] => (start, end, 1),
+            [start, end, step] => (start, end, step),
+            _ => unreachable!(),
+        };
+        if step == 0 {
+            return Err(InterpreterError::ZeroRangeStep(span).into());
+        }
+        Ok(Self {
+            next: Some(start),
+            end,
+            step,
+        })
+    }
+
+    fn len(&self) -> usize {
+        let Some(start) = self.next else { return 0 };
+        let distance = (self.end as i128 - start as i128) * self.step.signum() as i128;
+        let len = match distance {
+            ..=0 => 0,
+            distance => (distance - 1) / self.step.unsigned_abs() as i128 + 1,
+        };
+        usize::try_from(len).unwrap_or(usize::MAX)
+    }
+}
+
+impl Iterator for Range {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        let step = self.step;
+        let n = self.next.filter(|&n| (step > 0 && n < self.end) || (step < 0 && n > self.end))?;
+        // Stepping past the largest or smallest int also passes the end.
+        self.next = n.checked_add(step);
+        Some(n)
+    }
+}
+
+/// Functions on strings. Indices count characters, not bytes.
+fn insert_string_functions(functions: &mut HashMap<String, Builtin>) {
+    functions.insert("split".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("split".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "split", span)?;
+        let separator = expect_string(&params[1], 1, "split", span)?;
+        use_fuel(scope, s.len(), span)?;
+
+        // Splitting on "" gives the characters, not empty strings around them.
+        let parts: Vec<_> = if separator.is_empty() {
+            s.chars().map(|c| c.to_string()).collect()
+        } else {
+            s.split(separator).map(str::to_owned).collect()
+        };
+        Ok(string_array(parts))
+    });
+
+    functions.insert("join".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("join".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let array = expect_array(&params[0], 0, "join", span)?;
+        let separator = expect_string(&params[1], 1, "join", span)?;
+        use_fuel(scope, array.borrow().len(), span)?;
+
+        let joined = array
+            .borrow()
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(separator);
+        Ok(Rc::new(InterpreterValue::String(joined)))
+    });
+
+    // `(substr s start)` or `(substr s start end)`, like `slice`.
+    functions.insert("substr".to_string(), |scope, params, span| {
+        if params.len() != 2 && params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("substr".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "substr", span)?;
+        let len = s.chars().count();
+        let start = expect_int(&params[1], 1, "substr", span)?;
+        let end = match params.get(2) {
+            Some(end) => expect_int(end, 2, "substr", span)?,
+            None => len as i64,
+        };
+
+        let start = index(start, len + 1, span)?;
+        let end = index(end, len + 1, span)?;
+        let substr = s.chars().skip(start).take(end.saturating_sub(start)).collect();
+        Ok(Rc::new(InterpreterValue::String(substr)))
+    });
+
+    // The index of the first occurrence, or -1.
+    functions.insert("index_of".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("index_of".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "index_of", span)?;
+        let needle = expect_string(&params[1], 1, "index_of", span)?;
+
+        let index = match s.find(needle) {
+            Some(byte) => s[..byte].chars().count() as i64,
+            None => -1,
+        };
+        Ok(Rc::new(InterpreterValue::Int(index)))
+    });
+
+    functions.insert("replace".to_string(), |scope, params, span| {
+        if params.len() != 3 {
+            return Err(InterpreterError::InvalidFunctionCall("replace".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "replace", span)?;
+        let from = expect_string(&params[1], 1, "replace", span)?;
+        let to = expect_string(&params[2], 2, "replace", span)?;
+        use_fuel(scope, s.len(), span)?;
+        Ok(Rc::new(InterpreterValue::String(s.replace(from, to))))
+    });
+
+    functions.insert("upper".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("upper".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "upper", span)?;
+        use_fuel(scope, s.len(), span)?;
+        Ok(Rc::new(InterpreterValue::String(s.to_uppercase())))
+    });
+
+    functions.insert("lower".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("lower".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "lower", span)?;
+        use_fuel(scope, s.len(), span)?;
+        Ok(Rc::new(InterpreterValue::String(s.to_lowercase())))
+    });
+
+    functions.insert("trim".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("trim".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "trim", span)?;
+        Ok(Rc::new(InterpreterValue::String(s.trim().to_owned())))
+    });
+
+    functions.insert("chars".to_string(), |scope, params, span| {
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("chars".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "chars", span)?;
+        use_fuel(scope, s.len(), span)?;
+        Ok(string_array(s.chars().map(|c| c.to_string()).collect()))
+    });
+
+    functions.insert("starts_with".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(
+                InterpreterError::InvalidFunctionCall("starts_with".to_owned(), span).into(),
+            );
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "starts_with", span)?;
+        let prefix = expect_string(&params[1], 1, "starts_with", span)?;
+        Ok(Rc::new(InterpreterValue::Bool(s.starts_with(prefix))))
+    });
+
+    functions.insert("ends_with".to_string(), |scope, params, span| {
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("ends_with".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let s = expect_string(&params[0], 0, "ends_with", span)?;
+        let suffix = expect_string(&params[1], 1, "ends_with", span)?;
+        Ok(Rc::new(InterpreterValue::Bool(s.ends_with(suffix))))
+    });
+
+    // Replaces each `{}` in the first argument with the next of the rest.
+    // `{{` and `}}` are a literal brace.
+    functions.insert("format".to_string(), |scope, params, span| {
+        if params.is_empty() {
+            return Err(InterpreterError::InvalidFunctionCall("format".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let template = expect_string(&params[0], 0, "format", span)?;
+
+        let mut args = params[1..].iter();
+        let mut result = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    result.push(c);
+                }
+                ('{', Some('}')) => {
+                    chars.next();
+                    let Some(arg) = args.next() else {
+                        return Err(
+                            InterpreterError::InvalidFunctionCall("format".to_owned(), span).into(),
+                        );
+                    };
+                    result += &arg.to_string();
+                }
+                _ => result.push(c),
+            }
+        }
+        if args.next().is_some() {
+            return Err(InterpreterError::InvalidFunctionCall("format".to_owned(), span).into());
+        }
+        Ok(Rc::new(InterpreterValue::String(result)))
+    });
+}
+
+/// Functions that reach outside the program. They all fail unless the run
+/// allows I/O, so untrusted programs can't touch anything.
+fn insert_io_functions(functions: &mut HashMap<String, Builtin>) {
+    functions.insert("read_file".to_string(), |scope, params, span| {
+        check_io(scope, "read_file", span)?;
+        if params.len() != 1 {
+            return Err(InterpreterError::InvalidFunctionCall("read_file".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let path = expect_string(&params[0], 0, "read_file", span)?;
+
+        let contents = fs::read_to_string(path).map_err(|e| io_failed(e, "read_file", span))?;
+        Ok(Rc::new(InterpreterValue::String(contents)))
+    });
+
+    functions.insert("write_file".to_string(), |scope, params, span| {
+        check_io(scope, "write_file", span)?;
+        if params.len() != 2 {
+            return Err(InterpreterError::InvalidFunctionCall("write_file".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let path = expect_string(&params[0], 0, "write_file", span)?;
+        let contents = expect_string(&params[1], 1, "write_file", span)?;
+
+        fs::write(path, contents).map_err(|e| io_failed(e, "write_file", span))?;
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    functions.insert("append_file".to_string(), |scope, params, span| {
+        check_io(scope, "append_file", span)?;
+        if params.len() != 2 {
+            return Err(
+                InterpreterError::InvalidFunctionCall("append_file".to_owned(), span).into(),
+            );
+        }
+        let params = scope.evaluate_each(params)?;
+        let path = expect_string(&params[0], 0, "append_file", span)?;
+        let contents = expect_string(&params[1], 1, "append_file", span)?;
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| io_failed(e, "append_file", span))?;
+        Ok(Rc::new(InterpreterValue::Void))
+    });
+
+    // Reads a line from stdin, including the newline. At the end of the input
+    // the line is empty.
+    functions.insert("read_line".to_string(), |scope, params, span| {
+        check_io(scope, "read_line", span)?;
+        if !params.is_empty() {
+            return Err(InterpreterError::InvalidFunctionCall("read_line".to_owned(), span).into());
+        }
+
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| io_failed(e, "read_line", span))?;
+        Ok(Rc::new(InterpreterValue::String(line)))
+    });
+
+    functions.insert("args".to_string(), |scope, params, span| {
+        check_io(scope, "args", span)?;
+        if !params.is_empty() {
+            return Err(InterpreterError::InvalidFunctionCall("args".to_owned(), span).into());
+        }
+        Ok(string_array(scope.context.args.clone()))
+    });
+
+    // `(exit)` or `(exit code)`.
+    functions.insert("exit".to_string(), |scope, params, span| {
+        check_io(scope, "exit", span)?;
+        if params.len() > 1 {
+            return Err(InterpreterError::InvalidFunctionCall("exit".to_owned(), span).into());
+        }
+        let params = scope.evaluate_each(params)?;
+        let code = match params.first() {
+            Some(code) => expect_int(code, 0, "exit", span)?,
+            None => 0,
+        };
+        Err(Exit(code as i32).into())
+    });
+}
+
+fn check_io(scope: &InterpreterScope, name: &str, span: Span) -> Result<()> {
+    if scope.context.allow_io {
+        Ok(())
+    } else {
+        Err(InterpreterError::IoNotAllowed(name.to_owned(), span).into())
+    }
+}
+
+fn io_failed(error: io::Error, name: &str, span: Span) -> anyhow::Error {
+    InterpreterError::IoFailed(name.to_owned(), error.to_string(), span).into()
+}
+
+/// Uses up a step of the run's fuel for each of the `len` elements a native
+/// makes or goes through, so that one call can't do unlimited work.
+fn use_fuel(scope: &InterpreterScope, len: usize, span: Span) -> Result<()> {
+    scope.context.limits.take(len as u64, span)?;
+    Ok(())
+}
+
+fn string_array(strings: Vec<String>) -> Rc<InterpreterValue> {
+    Rc::new(InterpreterValue::Array(RefCell::new(
+        strings
+            .into_iter()
+            .map(|s| Rc::new(InterpreterValue::String(s)))
+            .collect(),
+    )))
+}
+
+/// Calls a callback that should return a bool.
+fn call_predicate(
+    scope: &mut InterpreterScope,
+    function: &Rc<InterpreterValue>,
+    value: Rc<InterpreterValue>,
+    name: &str,
+    span: Span,
+) -> Result<bool> {
+    let result = scope.call(function, vec![value], span)?;
+    match result.as_ref() {
+        InterpreterValue::Bool(b) => Ok(*b),
+        _ => Err(InterpreterError::InvalidType1Native(
+            result.get_type().to_string(),
+            name.to_owned(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Whether two values are equal. Ints and floats are compared by value,
+/// and arrays and dicts element by element. Returns `None` if they can't be
+/// compared, like an int and a string, or two functions.
+fn equal(a: &InterpreterValue, b: &InterpreterValue) -> Option<bool> {
+    equal_in(a, b, &mut Vec::new())
+}
+
+/// `equal`, where `comparing` holds the pairs of arrays being compared
+/// further out. Arrays that contain themselves lead back to one of those
+/// pairs, which is taken to be equal, since no difference has been found in
+/// it yet.
+fn equal_in(
+    a: &InterpreterValue,
+    b: &InterpreterValue,
+    comparing: &mut Vec<(*const InterpreterValue, *const InterpreterValue)>,
+) -> Option<bool> {
+    match (a, b) {
+        (InterpreterValue::Int(a), InterpreterValue::Int(b)) => Some(a == b),
+        (InterpreterValue::Int(a), InterpreterValue::Float(b))
+        | (InterpreterValue::Float(b), InterpreterValue::Int(a)) => Some(*a as f64 == *b),
+        (InterpreterValue::Float(a), InterpreterValue::Float(b)) => Some(a == b),
+        (InterpreterValue::String(a), InterpreterValue::String(b)) => Some(a == b),
+        (InterpreterValue::Bool(a), InterpreterValue::Bool(b)) => Some(a == b),
+        (InterpreterValue::Array(a_items), InterpreterValue::Array(b_items)) => {
+            let pair = (a as *const _, b as *const _);
+            if std::ptr::eq(a, b) || comparing.contains(&pair) {
+                return Some(true);
+            }
+            let (a, b) = (a_items.borrow(), b_items.borrow());
+            if a.len() != b.len() {
+                return Some(false);
+            }
+            comparing.push(pair);
+            let mut result = Some(true);
+            for (a, b) in a.iter().zip(b.iter()) {
+                match equal_in(a, b, comparing) {
+                    Some(true) => {}
+                    other => {
+                        result = other;
+                        break;
+                    }
+                }
+            }
+            comparing.pop();
+            result
+        }
+        (InterpreterValue::Dict(a), InterpreterValue::Dict(b)) => {
+            if a.len() != b.len() {
+                return Some(false);
+            }
+            for ((a_key, a), (b_key, b)) in a.iter().zip(b.iter()) {
+                if a_key != b_key || !equal_in(a, b, comparing)? {
+                    return Some(false);
+                }
+            }
+            Some(true)
+        }
+        _ => None,
+    }
+}
+
+/// Checks that the arguments of the native `name` can be compared, and
+/// whether they are equal.
+fn expect_comparable(
+    a: &InterpreterValue,
+    b: &InterpreterValue,
+    name: &str,
+    span: Span,
+) -> Result<bool> {
+    equal(a, b).ok_or_else(|| {
+        InterpreterError::InvalidType2Native(
+            a.get_type().to_string(),
+            b.get_type().to_string(),
+            name.to_owned(),
+            span,
+        )
+        .into()
+    })
+}
+
+/// Evaluates the arguments of `and` or `or` in order, stopping at the first
+/// one that is `stop_at`, which is then the result.
+fn short_circuit(
+    scope: &mut InterpreterScope,
+    params: &[AstNode],
+    stop_at: bool,
+    name: &str,
+    span: Span,
+) -> Result<Rc<InterpreterValue>> {
+    if params.is_empty() {
+        return Err(InterpreterError::InvalidFunctionCall(name.to_owned(), span).into());
+    }
+    for param in params {
+        let value = scope.evaluate(param)?;
+        match value.as_ref() {
+            InterpreterValue::Bool(b) if *b == stop_at => return Ok(value),
+            InterpreterValue::Bool(_) => {}
+            _ => {
+                return Err(InterpreterError::InvalidType1Native(
+                    value.get_type().to_string(),
+                    name.to_owned(),
+                    span,
+                )
+                .into())
+            }
+        }
+    }
+    Ok(Rc::new(InterpreterValue::Bool(!stop_at)))
+}
+
+/// A stable merge sort. The standard library's sorts can't stop on an error
+/// and may panic if the comparison isn't a total order, which a user's
+/// comparison needn't be.
+fn merge_sort(
+    mut values: Vec<Rc<InterpreterValue>>,
+    compare: &mut impl FnMut(&Rc<InterpreterValue>, &Rc<InterpreterValue>) -> Result<Ordering>,
+) -> Result<Vec<Rc<InterpreterValue>>> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let mut left = merge_sort(values, compare)?.into_iter().peekable();
+    let mut right = merge_sort(right, compare)?.into_iter().peekable();
+
+    let mut result = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if compare(l, r)? == Ordering::Greater {
+            result.extend(right.next());
+        } else {
+            result.extend(left.next());
+        }
+    }
+    result.extend(left);
+    result.extend(right);
+    Ok(result)
+}
+
+/// Checks that argument `i` of the native `name` can be called.
+fn expect_function(value: &InterpreterValue, i: usize, name: &str, span: Span) -> Result<()> {
+    match value {
+        InterpreterValue::Function { .. }
+        | InterpreterValue::Compiled { .. }
+        | InterpreterValue::NativeFunction { .. } => Ok(()),
+        _ => Err(InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            "function".to_owned(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Checks that `i` is an index into something of length `len`.
+fn index(i: i64, len: usize, span: Span) -> Result<usize> {
+    usize::try_from(i)
+        .ok()
+        .filter(|&index| index < len)
+        .ok_or_else(|| InterpreterError::IndexOutOfRange(i, len, span).into())
+}
+
+/// Checks that argument `i` of the native `name` is an int.
+fn expect_int(value: &InterpreterValue, i: usize, name: &str, span: Span) -> Result<i64> {
+    match value {
+        InterpreterValue::Int(int) => Ok(*int),
+        _ => Err(InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            "int".to_owned(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Checks that argument `i` of the native `name` is a string.
+fn expect_string<'a>(
+    value: &'a InterpreterValue,
+    i: usize,
+    name: &str,
+    span: Span,
+) -> Result<&'a str> {
+    match value {
+        InterpreterValue::String(s) => Ok(s),
+        _ => Err(InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            "string".to_owned(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Checks that argument `i` of the native `name` is an array.
+fn expect_array<'a>(
+    value: &'a InterpreterValue,
+    i: usize,
+    name: &str,
+    span: Span,
+) -> Result<&'a RefCell<Vec<Rc<InterpreterValue>>>> {
+    match value {
+        InterpreterValue::Array(array) => Ok(array),
+        _ => Err(InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            "array".to_owned(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Checks that argument `i` of the native `name` is a dict.
+fn expect_dict<'a>(
+    value: &'a InterpreterValue,
+    i: usize,
+    name: &str,
+    span: Span,
+) -> Result<&'a BTreeMap<DictKey, Rc<InterpreterValue>>> {
+    match value {
+        InterpreterValue::Dict(dict) => Ok(dict),
+        _ => Err(InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            "dict".to_owned(),
+            span,
+        )
+        .into()),
+    }
 }
@@ -0,0 +1,343 @@
+use std::fmt;
+
+use crate::interpreter::{InterpreterError, StackFrame, Traceback};
+use crate::lexer::LexError;
+use crate::module::{ModuleError, SourceMap};
+use crate::parser::ParseError;
+use crate::resolver::{ResolveError, ResolveErrors, ResolveWarning};
+
+/// A location in the source. `file` is the index of the file in the
+/// `SourceMap`, `line` and `col` are 1-based and point at the first
+/// character, `offset` is the index of that character and `len` is the
+/// number of characters covered (which may run over several lines).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub col: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Span {
+    /// The span from the start of `self` to the end of `end`.
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            len: (end.offset + end.len).saturating_sub(self.offset),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// An error that can point at the source that caused it.
+pub trait Diagnostic {
+    fn span(&self) -> Option<Span>;
+
+    /// A short suggestion shown under the source snippet.
+    fn hint(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Finds the diagnostic information for any of the interpreter's errors.
+fn diagnostic(error: &anyhow::Error) -> Option<&dyn Diagnostic> {
+    if let Some(e) = error.downcast_ref::<LexError>() {
+        return Some(e);
+    }
+    if let Some(e) = error.downcast_ref::<ParseError>() {
+        return Some(e);
+    }
+    if let Some(e) = error.downcast_ref::<InterpreterError>() {
+        return Some(e);
+    }
+    if let Some(e) = error.downcast_ref::<ModuleError>() {
+        return Some(e);
+    }
+    if let Some(e) = error.downcast_ref::<ResolveError>() {
+        return Some(e);
+    }
+    None
+}
+
+/// Renders an error rustc-style: the message, where it happened, the source
+/// line with the offending part underlined and a hint if there is one. Errors
+/// raised inside function calls are followed by the calls they unwound
+/// through. When the resolver finds several problems they are all rendered.
+pub fn render(error: &anyhow::Error, sources: &SourceMap) -> String {
+    if let Some(ResolveErrors(errors)) = error.downcast_ref::<ResolveErrors>() {
+        let mut out = errors
+            .iter()
+            .map(|e| render(&e.clone().into(), sources))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if errors.len() > 1 {
+            out += &format!("\nerror: found {} problems\n", errors.len());
+        }
+        return out;
+    }
+
+    let (error, frames) = match error.downcast_ref::<Traceback>() {
+        Some(traceback) => (&traceback.error, traceback.frames.as_slice()),
+        None => (error, [].as_slice()),
+    };
+
+    let mut out = format!("error: {}\n", error);
+
+    if let Some(diagnostic) = diagnostic(error) {
+        out += &render_details(diagnostic, sources);
+    }
+
+    if !frames.is_empty() {
+        out += &render_traceback(frames, sources);
+    }
+
+    out
+}
+
+/// Renders a warning in the same way as an error.
+pub fn render_warning(warning: &ResolveWarning, sources: &SourceMap) -> String {
+    format!("warning: {}\n{}", warning, render_details(warning, sources))
+}
+
+/// The source snippet and hint shown under a message.
+fn render_details(diagnostic: &dyn Diagnostic, sources: &SourceMap) -> String {
+    let mut out = String::new();
+    if let Some(span) = diagnostic.span() {
+        out += &render_snippet(span, sources);
+    }
+
+    if let Some(hint) = diagnostic.hint() {
+        out += &format!("  = hint: {}\n", hint);
+    }
+    out
+}
+
+/// Lists the calls an error unwound through, most recent last. Runs of the
+/// same call (deep recursion) are collapsed into one line, and so are tail
+/// calls that weren't kept.
+fn render_traceback(frames: &[StackFrame], sources: &SourceMap) -> String {
+    let mut out = "traceback (most recent call last):\n".to_string();
+
+    let mut frames = frames.iter().rev().peekable();
+    while let Some(frame) = frames.next() {
+        out += &format!(
+            "  {} called at {}:{}\n",
+            frame.name,
+            path(frame.span, sources),
+            frame.span
+        );
+        if frame.left_out > 0 {
+            out += &format!("  ... {} tail calls left out\n", frame.left_out);
+        }
+
+        let mut repeated = 0;
+        while frames.next_if_eq(&frame).is_some() {
+            repeated += 1;
+        }
+        if repeated > 0 {
+            out += &format!("  ... repeated {} more times\n", repeated);
+        }
+    }
+
+    out
+}
+
+/// The path of the file `span` is in.
+fn path(span: Span, sources: &SourceMap) -> String {
+    match sources.get(span.file) {
+        Some(file) => file.path.display().to_string(),
+        None => "<unknown>".to_owned(),
+    }
+}
+
+pub(crate) fn render_snippet(span: Span, sources: &SourceMap) -> String {
+    let span = match sources.get(span.file) {
+        Some(file) => clamp_to_last_line(span, &file.source),
+        None => span,
+    };
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let mut out = format!("{}--> {}:{}\n", gutter, path(span, sources), span);
+
+    let Some(file) = sources.get(span.file) else {
+        return out;
+    };
+    let Some(line) = file.source.lines().nth(span.line.saturating_sub(1)) else {
+        return out;
+    };
+
+    // Copy tabs from the line so the caret lines up however they render.
+    let padding = line
+        .chars()
+        .take(span.col.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let remaining = line.chars().count().saturating_sub(span.col.saturating_sub(1));
+    let underline = span.len.clamp(1, remaining.max(1));
+
+    out += &format!("{} |\n", gutter);
+    out += &format!("{} | {}\n", number, line);
+    out += &format!("{} | {}{}\n", gutter, padding, "^".repeat(underline));
+    out
+}
+
+/// Moves a span past the last line of `source`, as the end of a file ending
+/// in a newline is, to the end of the last line, so it has a line to show.
+fn clamp_to_last_line(span: Span, source: &str) -> Span {
+    let lines = source.lines().count();
+    match source.lines().last() {
+        Some(last) if span.line > lines => Span {
+            line: lines,
+            col: last.chars().count() + 1,
+            ..span
+        },
+        _ => span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    fn sources(source: &str) -> SourceMap {
+        let sources = SourceMap::default();
+        sources.add("test.thing", source.to_owned());
+        sources
+    }
+
+    #[test]
+    fn test_render_underlines_span() {
+        let error = InterpreterError::VariableNotFound(
+            "x".to_owned(),
+            Span {
+                file: 0,
+                line: 2,
+                col: 8,
+                offset: 17,
+                len: 1,
+            },
+        );
+        let rendered = render(&error.into(), &sources("main {\n    (+ 1 x)\n}\n"));
+        assert_eq!(
+            rendered,
+            "error: Variable `x` not found\n \
+             --> test.thing:2:8\n  \
+             |\n\
+             2 |     (+ 1 x)\n  \
+             |        ^\n  \
+             = hint: variables must be declared with `let` or `const` before they are used\n"
+        );
+    }
+
+    #[test]
+    fn test_render_clamps_multiline_span() {
+        let error = ParseError::UnexpectedEof(Span {
+            file: 0,
+            line: 1,
+            col: 6,
+            offset: 5,
+            len: 40,
+        });
+        let rendered = render(&error.into(), &sources("main {\n  (print 1)\n"));
+        assert!(rendered.contains("1 | main {\n  |      ^\n"));
+    }
+
+    #[test]
+    fn test_render_unclosed_call() {
+        let render_parse = |source: &str| {
+            let sources = sources(source);
+            let error = Parser::try_new(Lexer::new(source)).unwrap().parse().unwrap_err();
+            render(&error, &sources)
+        };
+        assert_eq!(
+            render_parse("main { (print 1"),
+            "error: Unclosed delimiter\n \
+             --> test.thing:1:8\n  \
+             |\n\
+             1 | main { (print 1\n  \
+             |        ^\n  \
+             = hint: this is never closed\n"
+        );
+        let rendered = render_parse("main {\n  fn f(a, b\n");
+        assert!(rendered.contains("2 |   fn f(a, b\n  |       ^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_clamps_end_of_file() {
+        let error = ParseError::UnexpectedEof(Span {
+            file: 0,
+            line: 3,
+            col: 1,
+            offset: 24,
+            len: 0,
+        });
+        let rendered = render(&error.into(), &sources("main {\n  (print (+ 1 2)\n"));
+        assert!(rendered.starts_with("error: Unexpected end of file\n --> test.thing:2:17\n"));
+        assert!(rendered.contains("2 |   (print (+ 1 2)\n  |                 ^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_traceback() {
+        let at = |line| Span {
+            file: 0,
+            line,
+            col: 5,
+            offset: 0,
+            len: 1,
+        };
+        let frame = |name: &str, line| StackFrame {
+            name: name.to_owned(),
+            span: at(line),
+            left_out: 0,
+        };
+        let error = Traceback {
+            error: InterpreterError::InvalidFunctionCall("+".to_owned(), at(2)).into(),
+            frames: vec![
+                frame("f", 3),
+                frame("f", 3),
+                frame("f", 3),
+                StackFrame {
+                    left_out: 40,
+                    ..frame("g", 9)
+                },
+                frame("run", 12),
+            ],
+        };
+        let rendered = render(&error.into(), &sources(""));
+        assert!(rendered.starts_with("error: Invalid function call for +\n"));
+        assert!(rendered.ends_with(
+            "traceback (most recent call last):\n  \
+             run called at test.thing:12:5\n  \
+             g called at test.thing:9:5\n  \
+             ... 40 tail calls left out\n  \
+             f called at test.thing:3:5\n  \
+             ... repeated 2 more times\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_every_resolve_error() {
+        let at = |col| Span {
+            file: 0,
+            line: 1,
+            col,
+            offset: col - 1,
+            len: 1,
+        };
+        let error = ResolveErrors(vec![
+            ResolveError::VariableNotFound("a".to_owned(), at(2)),
+            ResolveError::FunctionNotFound("b".to_owned(), at(4)),
+        ]);
+        let rendered = render(&error.into(), &sources("[a (b)]"));
+        assert!(rendered.starts_with("error: Variable `a` not found\n"));
+        assert!(rendered.contains("\n\nerror: Function `b` not found\n"));
+        assert!(rendered.ends_with("\nerror: found 2 problems\n"));
+    }
+}
@@ -0,0 +1,368 @@
+use anyhow::Result;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::default_fns::default_native_functions;
+use crate::diagnostic::{self, Span};
+use crate::interpreter::{
+    Context, Interpreter, InterpreterError, InterpreterScope, InterpreterValue, NativeFn,
+};
+use crate::lexer::Lexer;
+use crate::module::SourceMap;
+use crate::parser::Parser;
+use crate::resolver;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum EngineError {
+    #[error("Function `{0}` not found")]
+    FunctionNotFound(String),
+    #[error("`{0}` returned {1}, which isn't {2}")]
+    InvalidReturnType(String, String, &'static str),
+}
+
+/// Runs code for a program embedding the language. Like the REPL, it keeps
+/// one top scope, so what `eval_str` defines can be used by later calls, and
+/// the program can add natives of its own with `register_fn`.
+///
+/// ```
+/// let mut engine = ilt1::Engine::new();
+/// engine.register_fn("shout", |s: String| s.to_uppercase());
+/// engine.eval_str::<()>(r#"fn greet(name) { (shout (format "hi {}" name)) }"#).unwrap();
+/// let greeting: String = engine.call_function("greet", ("ann",)).unwrap();
+/// assert_eq!(greeting, "HI ANN");
+/// ```
+pub struct Engine {
+    interpreter: Interpreter,
+    sources: Rc<SourceMap>,
+    /// Where calls made by `call_function` show up in tracebacks.
+    host: Span,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// An engine with the default natives, which can't use I/O.
+    pub fn new() -> Self {
+        Self::with_context(Context::default())
+    }
+
+    pub fn with_context(context: Context) -> Self {
+        Self::with_natives(default_native_functions(), context)
+    }
+
+    /// An engine with only the natives in `functions`.
+    pub fn with_natives(functions: HashMap<String, NativeFn>, context: Context) -> Self {
+        let sources = Rc::new(SourceMap::default());
+        let host = Span {
+            file: sources.add("<host>", String::new()),
+            line: 1,
+            col: 1,
+            ..Default::default()
+        };
+        Self {
+            interpreter: Interpreter::new(Vec::new(), functions, sources.clone(), context),
+            sources,
+            host,
+        }
+    }
+
+    /// Defines a native that calls `function`. Its arguments are converted
+    /// from the values it is called with, and its result, or the error it
+    /// returns, is passed back to the program.
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl RegisterFn<Args>) -> &mut Self {
+        self.interpreter.define_native(name, function.into_native(name));
+        self
+    }
+
+    /// Evaluates `source` in the top scope and converts the value of its last
+    /// statement. Definitions in it are kept.
+    pub fn eval_str<R: FromValue>(&mut self, source: &str) -> Result<R> {
+        let value = self.eval_each("<eval>", source)?.pop();
+        let value = value.unwrap_or_else(|| Rc::new(InterpreterValue::Void));
+        R::from_value(&value).ok_or_else(|| {
+            EngineError::InvalidReturnType("<eval>".to_owned(), value.to_string(), R::TYPE).into()
+        })
+    }
+
+    /// Evaluates `source` in the top scope and returns the value of each top
+    /// level node in it. The source is kept as the file `path`, since
+    /// functions defined by it can fail later.
+    pub(crate) fn eval_each(
+        &mut self,
+        path: &str,
+        source: &str,
+    ) -> Result<Vec<Rc<InterpreterValue>>> {
+        let file = self.sources.add(path, source.to_owned());
+        let mut parser = Parser::try_new(Lexer::new(source).with_file(file))?;
+        let mut nodes = parser.parse_repl_input()?;
+        let warnings = resolver::resolve_statements(&mut nodes, self.interpreter.globals())?;
+        self.interpreter.warn(&warnings);
+        self.interpreter.context().limits.start();
+
+        let mut results = Vec::new();
+        for node in nodes.iter() {
+            results.push(self.interpreter.evaluate_top_level(node)?);
+        }
+        Ok(results)
+    }
+
+    /// Calls the function `name` of the top scope with `args`, a tuple of
+    /// Rust values, and converts its result.
+    pub fn call_function<R: FromValue>(&mut self, name: &str, args: impl IntoArgs) -> Result<R> {
+        let function = self
+            .interpreter
+            .globals()
+            .get(name)
+            .ok_or_else(|| EngineError::FunctionNotFound(name.to_owned()))?;
+        let mut scope = InterpreterScope::with_context(self.interpreter.context());
+        scope.context.limits.start();
+        let value = scope.call(&function, args.into_args(), self.host)?;
+        R::from_value(&value).ok_or_else(|| {
+            EngineError::InvalidReturnType(name.to_owned(), value.to_string(), R::TYPE).into()
+        })
+    }
+
+    /// Describes an error returned by the engine, with the code it points at.
+    pub fn render_error(&self, error: &anyhow::Error) -> String {
+        diagnostic::render(error, &self.sources)
+    }
+}
+
+/// Conversion of a Rust value to a value of the language.
+pub trait IntoValue {
+    fn into_value(self) -> Rc<InterpreterValue>;
+}
+
+/// Conversion of a value of the language to a Rust value, which fails if the
+/// value has another type.
+pub trait FromValue: Sized {
+    /// The type of value that converts, for errors.
+    const TYPE: &'static str;
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self>;
+}
+
+impl IntoValue for Rc<InterpreterValue> {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        self
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::Int(self))
+    }
+}
+
+impl IntoValue for i32 {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::Int(self.into()))
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::Float(self))
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::Bool(self))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::String(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        self.to_owned().into_value()
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        Rc::new(InterpreterValue::Void)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Rc<InterpreterValue> {
+        let values = self.into_iter().map(IntoValue::into_value).collect();
+        Rc::new(InterpreterValue::Array(RefCell::new(values)))
+    }
+}
+
+impl FromValue for Rc<InterpreterValue> {
+    const TYPE: &'static str = "any value";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    const TYPE: &'static str = "int";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        match value.as_ref() {
+            InterpreterValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+/// Ints are promoted to floats, as in arithmetic.
+impl FromValue for f64 {
+    const TYPE: &'static str = "float";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        match value.as_ref() {
+            InterpreterValue::Int(i) => Some(*i as f64),
+            InterpreterValue::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    const TYPE: &'static str = "bool";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        match value.as_ref() {
+            InterpreterValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    const TYPE: &'static str = "string";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        match value.as_ref() {
+            InterpreterValue::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Any value converts, for results that aren't needed.
+impl FromValue for () {
+    const TYPE: &'static str = "any value";
+
+    fn from_value(_: &Rc<InterpreterValue>) -> Option<Self> {
+        Some(())
+    }
+}
+
+/// Arrays convert if all their elements do.
+impl<T: FromValue> FromValue for Vec<T> {
+    const TYPE: &'static str = "array";
+
+    fn from_value(value: &Rc<InterpreterValue>) -> Option<Self> {
+        match value.as_ref() {
+            InterpreterValue::Array(array) => array.borrow().iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+/// What a registered function can return: a value, or a `Result` whose
+/// error becomes the program's error.
+pub trait NativeResult {
+    fn into_result(self) -> Result<Rc<InterpreterValue>>;
+}
+
+impl<T: IntoValue> NativeResult for T {
+    fn into_result(self) -> Result<Rc<InterpreterValue>> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> NativeResult for Result<T> {
+    fn into_result(self) -> Result<Rc<InterpreterValue>> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+/// Rust functions and closures that can be registered as natives. `Args` is
+/// the tuple of their argument types.
+pub trait RegisterFn<Args> {
+    fn into_native(self, name: &str) -> NativeFn;
+}
+
+/// Arguments to `Engine::call_function`, as a tuple.
+pub trait IntoArgs {
+    fn into_args(self) -> Vec<Rc<InterpreterValue>>;
+}
+
+/// Converts argument `i` of the registered function `name`.
+fn argument<T: FromValue>(
+    (i, value): (usize, &Rc<InterpreterValue>),
+    name: &str,
+    span: Span,
+) -> Result<T> {
+    T::from_value(value).ok_or_else(|| {
+        InterpreterError::InvalidTypeArgNative(
+            value.get_type().to_string(),
+            i,
+            name.to_owned(),
+            T::TYPE.to_owned(),
+            span,
+        )
+        .into()
+    })
+}
+
+macro_rules! impl_function_traits {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> RegisterFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeResult,
+            $($arg: FromValue,)*
+        {
+            fn into_native(self, name: &str) -> NativeFn {
+                let name = name.to_owned();
+                NativeFn::new(move |scope, params, span| {
+                    if params.len() != <[&str]>::len(&[$(stringify!($arg)),*]) {
+                        let error = InterpreterError::InvalidFunctionCall(name.clone(), span);
+                        return Err(error.into());
+                    }
+                    #[allow(unused_variables, unused_mut)]
+                    let mut values = scope.evaluate_each(params)?.into_iter();
+                    #[allow(unused_variables, unused_mut)]
+                    let mut i = 0;
+                    self($({
+                        i += 1;
+                        argument::<$arg>((i - 1, &values.next().unwrap()), &name, span)?
+                    }),*)
+                    .into_result()
+                })
+            }
+        }
+
+        impl<$($arg: IntoValue),*> IntoArgs for ($($arg,)*) {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Rc<InterpreterValue>> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_value()),*]
+            }
+        }
+    };
+}
+
+impl_function_traits!();
+impl_function_traits!(A);
+impl_function_traits!(A, B);
+impl_function_traits!(A, B, C);
+impl_function_traits!(A, B, C, D);
+impl_function_traits!(A, B, C, D, E);
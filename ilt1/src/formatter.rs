@@ -0,0 +1,493 @@
+use std::path::Path;
+
+use crate::ast::{AstNode, AstNodeType};
+use crate::diagnostic::Span;
+
+/// The width lines are kept within where possible.
+const WIDTH: usize = 80;
+const INDENT: usize = 4;
+
+/// A layout-independent description of the output. Groups are printed on
+/// one line if they fit, and otherwise every `Line` directly in them is a
+/// line break.
+#[derive(Debug)]
+enum Doc {
+    Text(String),
+    /// A space, or a line break if the enclosing group doesn't fit.
+    Line,
+    /// A line break, which also stops the enclosing groups from being flat.
+    HardLine,
+    /// Indents the lines in it further.
+    Nest(Box<Doc>),
+    /// Indents the lines in it to the column it starts at.
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+fn join(docs: Vec<Doc>, separator: impl Fn() -> Doc) -> Doc {
+    let mut joined = Vec::new();
+    for (i, doc) in docs.into_iter().enumerate() {
+        if i > 0 {
+            joined.push(separator());
+        }
+        joined.push(doc);
+    }
+    Doc::Concat(joined)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Formats a parsed program. `source` is the text it was parsed from, which
+/// literals are copied from as they were written, and which is checked for
+/// blank lines to keep.
+///
+/// Strings on their own are how programs are commented, so a string that
+/// started on the same line as the end of the statement before it stays on
+/// that line.
+pub fn format(ast: &[AstNode], source: &str) -> String {
+    let formatter = Formatter {
+        source: source.chars().collect(),
+    };
+    let mut out = print(&formatter.statements(ast));
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// The name a module is imported under when no alias is given.
+fn default_alias(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// Writes `s` as a string literal.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Formatter {
+    source: Vec<char>,
+}
+
+impl Formatter {
+    /// The source text of `span`.
+    fn source(&self, span: Span) -> String {
+        self.source[span.offset..span.offset + span.len].iter().collect()
+    }
+
+    /// The line `span` ends on.
+    fn end_line(&self, span: Span) -> usize {
+        let text = &self.source[span.offset..span.offset + span.len];
+        span.line + text.iter().filter(|c| **c == '\n').count()
+    }
+
+    /// Statements one per line, keeping single blank lines between them and
+    /// comments on the line they were on.
+    fn statements(&self, nodes: &[AstNode]) -> Doc {
+        let mut docs = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 {
+                let end = self.end_line(nodes[i - 1].span);
+                if matches!(node.ty, AstNodeType::String(_)) && node.span.line == end {
+                    docs.push(text(" "));
+                } else {
+                    docs.push(Doc::HardLine);
+                    if node.span.line > end + 1 {
+                        docs.push(Doc::HardLine);
+                    }
+                }
+            }
+            docs.push(self.node(node));
+        }
+        Doc::Concat(docs)
+    }
+
+    /// A block, which is broken over several lines unless `flat` is set and
+    /// it is a single statement that fits on one.
+    fn block(&self, nodes: &[AstNode], flat: bool) -> Doc {
+        if nodes.is_empty() {
+            return text("{}");
+        }
+        if flat && nodes.len() == 1 {
+            return Doc::Group(Box::new(Doc::Concat(vec![
+                text("{"),
+                Doc::Nest(Box::new(Doc::Concat(vec![Doc::Line, self.node(&nodes[0])]))),
+                Doc::Line,
+                text("}"),
+            ])));
+        }
+        Doc::Concat(vec![
+            text("{"),
+            Doc::Nest(Box::new(Doc::Concat(vec![Doc::HardLine, self.statements(nodes)]))),
+            Doc::HardLine,
+            text("}"),
+        ])
+    }
+
+    /// The body of a function. Only lambdas' bodies can be kept on one line.
+    fn body(&self, node: &AstNode, flat: bool) -> Doc {
+        match &node.ty {
+            AstNodeType::Block(nodes) => self.block(nodes, flat),
+            _ => self.node(node),
+        }
+    }
+
+    /// `open`, the items separated by `separator` and `close`, with the items
+    /// lined up under the first one if they don't fit on one line.
+    fn list(&self, open: &str, items: Vec<Doc>, separator: &str, close: &str) -> Doc {
+        if items.is_empty() {
+            return text(format!("{}{}", open, close));
+        }
+        let items = join(items, || Doc::Concat(vec![text(separator), Doc::Line]));
+        Doc::Group(Box::new(Doc::Concat(vec![
+            text(open),
+            Doc::Align(Box::new(items)),
+            text(close),
+        ])))
+    }
+
+    fn node(&self, node: &AstNode) -> Doc {
+        match &node.ty {
+            AstNodeType::Int(_) | AstNodeType::Float(_) | AstNodeType::String(_) => {
+                text(self.source(node.span))
+            }
+            AstNodeType::Bool(value) => text(value.to_string()),
+            AstNodeType::Ident(name, _) => text(name),
+            AstNodeType::Keyword(keyword) => text(format!("{:?}", keyword).to_lowercase()),
+            AstNodeType::Fn {
+                name, params, body, ..
+            } => {
+                let params = params
+                    .iter()
+                    .map(|param| self.node(param))
+                    .collect::<Vec<_>>();
+                let head = match name {
+                    Some(name) => format!("fn {}(", name),
+                    None => "fn(".to_owned(),
+                };
+                Doc::Concat(vec![
+                    self.list(&head, params, ",", ")"),
+                    text(" "),
+                    self.body(body, name.is_none()),
+                ])
+            }
+            AstNodeType::Const { name, value, .. } => self.declaration("const", name, value),
+            AstNodeType::Let { name, value, .. } => self.declaration("let", name, value),
+            AstNodeType::Set { name, value, .. } => self.declaration("set", name, value),
+            AstNodeType::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                let mut docs = vec![
+                    text("if "),
+                    self.node(condition),
+                    text(" "),
+                    self.node(body),
+                ];
+                if let Some(else_body) = else_body {
+                    docs.push(text(" else "));
+                    docs.push(self.node(else_body));
+                }
+                Doc::Concat(docs)
+            }
+            AstNodeType::While { condition, body } => Doc::Concat(vec![
+                text("while "),
+                self.node(condition),
+                text(" "),
+                self.node(body),
+            ]),
+            AstNodeType::For {
+                name,
+                iterable,
+                body,
+            } => Doc::Concat(vec![
+                text(format!("for {} in ", name)),
+                self.node(iterable),
+                text(" "),
+                self.node(body),
+            ]),
+            AstNodeType::Return(None) => text("return"),
+            AstNodeType::Return(Some(value)) => {
+                Doc::Concat(vec![text("return "), self.node(value)])
+            }
+            AstNodeType::Break => text("break"),
+            AstNodeType::Continue => text("continue"),
+            AstNodeType::Main(body) => Doc::Concat(vec![text("main "), self.node(body)]),
+            AstNodeType::Import { path, alias } => {
+                let mut import = format!("import {}", quote(path));
+                if *alias != default_alias(path) {
+                    import += &format!(" as {}", alias);
+                }
+                text(import)
+            }
+            AstNodeType::Call { name, params, .. } => {
+                if params.is_empty() {
+                    return text(format!("({})", name));
+                }
+                // A lambda passed last follows the other arguments, and its
+                // body is indented like a block rather than lined up with
+                // them.
+                let (params, lambda) = match params.split_last() {
+                    Some((last, rest)) if matches!(last.ty, AstNodeType::Fn { name: None, .. }) => {
+                        (rest, Some(last))
+                    }
+                    _ => (params.as_slice(), None),
+                };
+                let params = params.iter().map(|param| self.node(param)).collect();
+                let Some(lambda) = lambda else {
+                    return self.list(&format!("({} ", name), params, "", ")");
+                };
+                let head = if params.is_empty() {
+                    text(format!("({}", name))
+                } else {
+                    self.list(&format!("({} ", name), params, "", "")
+                };
+                Doc::Concat(vec![
+                    head,
+                    text(" "),
+                    self.node(lambda),
+                    text(")"),
+                ])
+            }
+            AstNodeType::Block(nodes) => self.block(nodes, false),
+            AstNodeType::Array(nodes) => {
+                let nodes = nodes.iter().map(|node| self.node(node)).collect();
+                self.list("[", nodes, "", "]")
+            }
+            AstNodeType::Dict(entries) => {
+                let entries = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        Doc::Concat(vec![self.node(key), text(" "), self.node(value)])
+                    })
+                    .collect();
+                self.list("#{", entries, ",", "}")
+            }
+        }
+    }
+
+    fn declaration(&self, keyword: &str, name: &str, value: &AstNode) -> Doc {
+        Doc::Concat(vec![text(format!("{} {} ", keyword, name)), self.node(value)])
+    }
+}
+
+/// Lays out `doc`, breaking the groups that don't fit in `WIDTH`.
+fn print(doc: &Doc) -> String {
+    let mut printer = Printer::default();
+    let mut stack = vec![(0, Mode::Break, doc)];
+    while let Some((indent, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => printer.write(s),
+            Doc::Line if mode == Mode::Flat => printer.write(" "),
+            Doc::Line | Doc::HardLine => printer.newline(indent),
+            Doc::Nest(doc) => stack.push((indent + INDENT, mode, doc)),
+            Doc::Align(doc) => stack.push((printer.column, mode, doc)),
+            Doc::Group(doc) => {
+                let width = WIDTH as isize - printer.column as isize;
+                let mode = if mode == Mode::Flat || fits(doc, &stack, width) {
+                    Mode::Flat
+                } else {
+                    Mode::Break
+                };
+                stack.push((indent, mode, doc));
+            }
+            Doc::Concat(docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((indent, mode, doc));
+                }
+            }
+        }
+    }
+    printer.out
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    column: usize,
+    /// Indentation is written with the first text on a line, so blank lines
+    /// don't end up with trailing spaces.
+    indent_pending: bool,
+}
+
+impl Printer {
+    fn write(&mut self, s: &str) {
+        if self.indent_pending {
+            self.out.extend(std::iter::repeat_n(' ', self.column));
+            self.indent_pending = false;
+        }
+        self.out += s;
+        self.column = match s.rfind('\n') {
+            Some(newline) => s[newline + 1..].chars().count(),
+            None => self.column + s.chars().count(),
+        };
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.column = indent;
+        self.indent_pending = true;
+    }
+}
+
+/// Whether `doc` fits in `width` when printed flat, along with whatever
+/// follows it on the same line in `rest`.
+fn fits(doc: &Doc, rest: &[(usize, Mode, &Doc)], mut width: isize) -> bool {
+    let mut stack = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+    loop {
+        if width < 0 {
+            return false;
+        }
+        let (mode, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some((_, mode, doc)) => (*mode, *doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) => width -= s.chars().count() as isize,
+            Doc::Line if mode == Mode::Flat => width -= 1,
+            Doc::Line => return true,
+            Doc::HardLine => return mode == Mode::Break,
+            Doc::Nest(doc) | Doc::Align(doc) | Doc::Group(doc) => stack.push((mode, doc)),
+            Doc::Concat(docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((mode, doc));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    fn fmt(input: &str) -> String {
+        let ast = Parser::try_new(Lexer::new(input)).unwrap().parse().unwrap();
+        format(&ast, input)
+    }
+
+    #[test]
+    fn test_canonical_layout() {
+        let input = r#"
+import   "lib/math.thing"  as m
+fn add(a,b){(+ a b)}
+const NAMES ["a", "b" ,"c"]
+main{
+  let d #{"a" 1 "b" 2}
+     if (== (add 1 2) 3) { (print "yes") } else if false {} else { (print "no") }
+  while true { break }
+  for name in   NAMES {continue}
+  (map NAMES fn(name) { (print name) })
+}
+"#;
+        assert_eq!(
+            fmt(input),
+            r#"import "lib/math.thing" as m
+fn add(a, b) {
+    (+ a b)
+}
+const NAMES ["a" "b" "c"]
+main {
+    let d #{"a" 1, "b" 2}
+    if (== (add 1 2) 3) {
+        (print "yes")
+    } else if false {} else {
+        (print "no")
+    }
+    while true {
+        break
+    }
+    for name in NAMES {
+        continue
+    }
+    (map NAMES fn(name) { (print name) })
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_long_calls_are_wrapped() {
+        let input = r#"
+main {
+    (print (+ (some_function alpha beta gamma) (another_function_with_a_long_name alpha beta) 2.))
+    (for_each [1 2 3] fn(x) { let y (* x x) (print y) })
+}
+"#;
+        assert_eq!(
+            fmt(input),
+            r#"main {
+    (print (+ (some_function alpha beta gamma)
+              (another_function_with_a_long_name alpha beta)
+              2.))
+    (for_each [1 2 3] fn(x) {
+        let y (* x x)
+        (print y)
+    })
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_keeps_comments_and_blank_lines() {
+        let input = r#""
+A doc comment, kept as it is.
+    "
+"Attached to the function."
+fn f() {
+    "Returns one."
+
+
+    1 "one"
+}
+
+main {}
+"#;
+        // Runs of blank lines are collapsed to one.
+        assert_eq!(fmt(input), input.replace("\n\n\n", "\n\n"));
+    }
+
+    #[test]
+    fn test_formatting_is_stable() {
+        for input in [
+            include_str!("../examples.thing"),
+            include_str!("../hi.thing"),
+            include_str!("../conversions.thing"),
+            include_str!("../test.thing"),
+            include_str!("../bench.thing"),
+        ] {
+            let formatted = fmt(input);
+            assert_eq!(fmt(&formatted), formatted);
+        }
+    }
+}
@@ -1,56 +1,352 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+    time::Duration,
+};
 
 use anyhow::{Error, Result};
 
 use crate::{
-    ast::{AstNode, AstNodeType},
+    ast::{AstNode, AstNodeType, Slot},
+    compiler::{self, CompiledFunction},
+    debugger::Debugger,
+    default_fns::Range,
+    diagnostic::{self, Diagnostic, Span},
+    limits::Limits,
+    module::{ModuleLoader, SourceMap},
+    resolver::{self, ResolveWarning},
     token::Keyword,
+    vm,
 };
 
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum InterpreterError {
-    // TODO: Add line and column numbers to all errors
-    #[error("Variable {0} not found at {1}:{2}")]
-    VariableNotFound(String, usize, usize),
-    #[error("Function {0} not found at {1}:{2}")]
-    FunctionNotFound(String, usize, usize),
-    #[error("Invalid const value {0:?} at {1}:{2}")]
-    InvalidConstValue(AstNode, usize, usize),
-    #[error("Multiple main functions found. First at {0}:{1}, second at {2}:{3}")]
-    MultipleMainFunctions(usize, usize, usize, usize),
+    #[error("Variable `{0}` not found")]
+    VariableNotFound(String, Span),
+    #[error("Function `{0}` not found")]
+    FunctionNotFound(String, Span),
+    #[error("Cannot assign to constant `{0}`")]
+    AssignToConstant(String, Span),
+    #[error("Invalid const value")]
+    InvalidConstValue(Span),
+    #[error("Multiple main functions found. The first is at {0}")]
+    MultipleMainFunctions(Span, Span),
     #[error("No main function found")]
     NoMainFunction,
-    #[error("Main in inner scope. Found at {0}:{1}")]
-    MainInInnerScope(usize, usize),
+    #[error("Main in inner scope")]
+    MainInInnerScope(Span),
+    #[error("Import in inner scope")]
+    ImportInInnerScope(Span),
     #[error("Invalid function call for {0}")]
-    InvalidFunctionCall(String),
+    InvalidFunctionCall(String, Span),
     #[error("Invalid types {0} for {1}")]
-    InvalidType1Native(String, String),
+    InvalidType1Native(String, String, Span),
     #[error("Invalid types {0} and {1} for {2}")]
-    InvalidType2Native(String, String, String),
+    InvalidType2Native(String, String, String, Span),
     #[error("Invalid type {0} at argument {1} for {2}. Expected type: {3}")]
-    InvalidTypeArgNative(String, usize, String, String),
+    InvalidTypeArgNative(String, usize, String, String, Span),
+    #[error("Unexpected keyword {0:?}")]
+    UnexpectedKeyword(Keyword, Span),
+    #[error("Unexpected {0} escaping the expression")]
+    UnexpectedControlFlow(String, Span),
+    #[error("Cannot convert {0:?} to {1}")]
+    InvalidNumber(String, String, Span),
+    #[error("Invalid dict key of type {0}")]
+    InvalidDictKey(String, Span),
+    #[error("Key {0} not found")]
+    KeyNotFound(String, Span),
+    #[error("Index {0} out of range for length {1}")]
+    IndexOutOfRange(i64, usize, Span),
+    #[error("Integer overflow in {0}")]
+    IntegerOverflow(String, Span),
+    #[error("Division by zero")]
+    DivisionByZero(Span),
+    #[error("Step of range cannot be 0")]
+    ZeroRangeStep(Span),
+    #[error("`{0}` needs I/O access")]
+    IoNotAllowed(String, Span),
+    #[error("I/O error in {0}: {1}")]
+    IoFailed(String, String, Span),
+    #[error("Ran out of fuel after {0} steps")]
+    OutOfFuel(u64, Span),
+    #[error("More than {0} calls in progress")]
+    CallDepthExceeded(usize, Span),
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration, Span),
 }
 
-pub type NativeFn = fn(&mut InterpreterScope, &Vec<AstNode>) -> Result<Rc<InterpreterValue>>;
+impl InterpreterError {
+    /// The error `error` is, if a run was stopped by one of its `Limits`
+    /// (possibly inside calls).
+    pub fn limit(error: &Error) -> Option<&InterpreterError> {
+        let error = match error.downcast_ref::<Traceback>() {
+            Some(traceback) => &traceback.error,
+            None => error,
+        };
+        error.downcast_ref::<InterpreterError>().filter(|error| {
+            matches!(
+                error,
+                Self::OutOfFuel(..) | Self::CallDepthExceeded(..) | Self::Timeout(..)
+            )
+        })
+    }
+}
 
-#[derive(Debug, Clone)]
+impl Diagnostic for InterpreterError {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::NoMainFunction => None,
+            Self::VariableNotFound(_, span)
+            | Self::FunctionNotFound(_, span)
+            | Self::AssignToConstant(_, span)
+            | Self::InvalidConstValue(span)
+            | Self::MultipleMainFunctions(_, span)
+            | Self::MainInInnerScope(span)
+            | Self::ImportInInnerScope(span)
+            | Self::InvalidFunctionCall(_, span)
+            | Self::InvalidType1Native(_, _, span)
+            | Self::InvalidType2Native(_, _, _, span)
+            | Self::InvalidTypeArgNative(_, _, _, _, span)
+            | Self::UnexpectedKeyword(_, span)
+            | Self::UnexpectedControlFlow(_, span)
+            | Self::InvalidNumber(_, _, span)
+            | Self::InvalidDictKey(_, span)
+            | Self::KeyNotFound(_, span)
+            | Self::IndexOutOfRange(_, _, span)
+            | Self::IntegerOverflow(_, span)
+            | Self::DivisionByZero(span)
+            | Self::ZeroRangeStep(span)
+            | Self::IoNotAllowed(_, span)
+            | Self::IoFailed(_, _, span)
+            | Self::OutOfFuel(_, span)
+            | Self::CallDepthExceeded(_, span)
+            | Self::Timeout(_, span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::VariableNotFound(..) => {
+                Some("variables must be declared with `let` or `const` before they are used")
+            }
+            Self::FunctionNotFound(..) => Some("functions are declared with `fn`"),
+            Self::AssignToConstant(..) => Some("declare it with `let` to be able to change it"),
+            Self::InvalidConstValue(_) => {
+                Some("top level constants must be literals, arrays of literals or functions")
+            }
+            Self::MultipleMainFunctions(..) => Some("a program can only have one `main` block"),
+            Self::NoMainFunction => Some("add a `main { ... }` block to run the program"),
+            Self::MainInInnerScope(_) => Some("`main` can only be declared at the top level"),
+            Self::ImportInInnerScope(_) => Some("`import` can only be used at the top level"),
+            Self::InvalidFunctionCall(..) => Some("check the number of arguments"),
+            Self::UnexpectedControlFlow(..) => {
+                Some("`return`, `break` and `continue` cannot be used in arguments to natives")
+            }
+            Self::InvalidNumber(..) => {
+                Some("ints are written like `-42` and floats like `4.2`, without spaces")
+            }
+            Self::InvalidDictKey(..) => Some("dict keys must be ints, strings or bools"),
+            Self::KeyNotFound(..) => {
+                Some("check with `has_key` first, or pass a default to `dict_get`")
+            }
+            Self::IndexOutOfRange(..) => Some("indices start at 0; use `len` to check the length"),
+            Self::IntegerOverflow(..) => {
+                Some("ints are 64 bits; use `float` for numbers outside that range")
+            }
+            Self::DivisionByZero(_) => Some("check that the divisor isn't 0 first"),
+            Self::IoNotAllowed(..) => {
+                Some("run with `--allow-io` to let the program use files, stdin and the process")
+            }
+            Self::OutOfFuel(..) | Self::Timeout(..) => Some("check for loops that never end"),
+            Self::CallDepthExceeded(..) => {
+                Some("check for recursion that never ends, or make the recursive call a tail call")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A call to a user defined function that was in progress when an error
+/// happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub name: String,
+    /// Where the function was called from.
+    pub span: Span,
+    /// How many of the tail calls that replaced this one were left out of
+    /// the traceback.
+    pub left_out: usize,
+}
+
+/// An error raised inside one or more function calls, along with the calls it
+/// unwound through. Displays as the original error.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct Traceback {
+    pub error: Error,
+    /// The calls, innermost first.
+    pub frames: Vec<StackFrame>,
+}
+
+impl Traceback {
+    /// Records that `error` unwound out of the call in `frame`.
+    pub fn push(mut error: Error, frame: StackFrame) -> Error {
+        // Added in place, since making a new error can capture a backtrace,
+        // which takes longer the deeper the stack is.
+        if let Some(traceback) = error.downcast_mut::<Traceback>() {
+            traceback.frames.push(frame);
+            return error;
+        }
+        Traceback {
+            error,
+            frames: vec![frame],
+        }
+        .into()
+    }
+}
+
+/// How many of the latest calls replaced by tail calls are kept for
+/// tracebacks, besides the first.
+const TAIL_CALLS_KEPT: usize = 8;
+
+/// The calls that were replaced by tail calls, oldest first, so that
+/// tracebacks can still show them. A tail recursive loop can make any number
+/// of them, so only the first and the latest few are kept, and the rest are
+/// counted. Each call is kept as the function called, `F`, and where it was
+/// called from, which are cheap to copy.
+#[derive(Debug)]
+pub(crate) struct TailCalls<F> {
+    first: Option<(F, Span)>,
+    latest: VecDeque<(F, Span)>,
+    left_out: usize,
+}
+
+impl<F> Default for TailCalls<F> {
+    fn default() -> Self {
+        Self {
+            first: None,
+            latest: VecDeque::new(),
+            left_out: 0,
+        }
+    }
+}
+
+impl<F> TailCalls<F> {
+    /// Records that the call to `function` made at `span` was replaced by a
+    /// tail call.
+    pub(crate) fn push(&mut self, function: F, span: Span) {
+        if self.first.is_none() {
+            self.first = Some((function, span));
+            return;
+        }
+        if self.latest.len() == TAIL_CALLS_KEPT {
+            self.latest.pop_front();
+            self.left_out += 1;
+        }
+        self.latest.push_back((function, span));
+    }
+
+    /// Records that `error` unwound out of the calls, after the one that
+    /// replaced them. `name` gives the name of a function.
+    pub(crate) fn unwind(self, mut error: Error, name: impl Fn(&F) -> &str) -> Error {
+        let frame = |(function, span): (F, Span), left_out| StackFrame {
+            name: name(&function).to_owned(),
+            span,
+            left_out,
+        };
+        for call in self.latest.into_iter().rev() {
+            error = Traceback::push(error, frame(call, 0));
+        }
+        if let Some(call) = self.first {
+            error = Traceback::push(error, frame(call, self.left_out));
+        }
+        error
+    }
+}
+
+/// Raised by the `exit` native to stop the program. It unwinds like an error
+/// so everything on the way out is dropped normally.
+#[derive(Debug, thiserror::Error)]
+#[error("Exited with code {0}")]
+pub struct Exit(pub i32);
+
+impl Exit {
+    /// The exit code, if `error` is an exit (possibly from inside calls).
+    pub fn code(error: &Error) -> Option<i32> {
+        let error = match error.downcast_ref::<Traceback>() {
+            Some(traceback) => &traceback.error,
+            None => error,
+        };
+        error.downcast_ref::<Exit>().map(|exit| exit.0)
+    }
+}
+
+/// The name given to function values created from lambdas.
+pub const ANONYMOUS_FUNCTION_NAME: &str = "Anonymous Function";
+
+/// The signature of natives: they receive the unevaluated arguments and the
+/// span of the call, for errors.
+pub type NativeBody =
+    dyn Fn(&mut InterpreterScope, &Vec<AstNode>, Span) -> Result<Rc<InterpreterValue>>;
+
+/// A function implemented in Rust. Natives registered by a host program can
+/// hold on to state, so they are shared closures rather than plain functions.
+#[derive(Clone)]
+pub struct NativeFn(Rc<NativeBody>);
+
+impl NativeFn {
+    pub fn new(
+        body: impl Fn(&mut InterpreterScope, &Vec<AstNode>, Span) -> Result<Rc<InterpreterValue>>
+            + 'static,
+    ) -> Self {
+        Self(Rc::new(body))
+    }
+
+    pub fn call(
+        &self,
+        scope: &mut InterpreterScope,
+        params: &Vec<AstNode>,
+        span: Span,
+    ) -> Result<Rc<InterpreterValue>> {
+        (self.0)(scope, params, span)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NativeFn")
+    }
+}
+
+#[derive(Clone)]
 pub enum InterpreterValue {
     Int(i64),
     Float(f64),
     String(String),
     Bool(bool),
-    Array(Vec<Rc<InterpreterValue>>),
+    /// Arrays are shared: changing one through any reference changes it
+    /// everywhere.
+    Array(RefCell<Vec<Rc<InterpreterValue>>>),
+    Dict(BTreeMap<DictKey, Rc<InterpreterValue>>),
     Void,
     Function {
         name: String,
         params: Vec<String>,
         body: Box<AstNode>,
+        env: Rc<Environment>,
     },
     NativeFunction {
         name: String,
         body: NativeFn,
     },
+    /// A function compiled for the VM.
+    Compiled {
+        function: Rc<CompiledFunction>,
+        env: Rc<Environment>,
+    },
 }
 
 impl InterpreterValue {
@@ -61,8 +357,9 @@ impl InterpreterValue {
             Self::String(_) => "string",
             Self::Bool(_) => "bool",
             Self::Array(_) => "array",
+            Self::Dict(_) => "dict",
             Self::Void => "void",
-            Self::Function { .. } => "function",
+            Self::Function { .. } | Self::Compiled { .. } => "function",
             Self::NativeFunction { .. } => "native_function",
         }
     }
@@ -73,25 +370,100 @@ impl InterpreterValue {
             _ => self.to_string(),
         }
     }
+}
 
-    pub fn to_string(&self) -> String {
+thread_local! {
+    /// The arrays being formatted on this thread, outermost first.
+    static FORMATTING: RefCell<Vec<*const InterpreterValue>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Formats the array `value` with `write`, or as `[...]` if it is already
+/// being formatted further out, so an array that contains itself can be
+/// printed.
+fn format_array(
+    value: &InterpreterValue,
+    f: &mut fmt::Formatter<'_>,
+    write: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    let array = value as *const InterpreterValue;
+    if FORMATTING.with(|arrays| arrays.borrow().contains(&array)) {
+        return write!(f, "[...]");
+    }
+    FORMATTING.with(|arrays| arrays.borrow_mut().push(array));
+    let result = write(f);
+    FORMATTING.with(|arrays| arrays.borrow_mut().pop());
+    result
+}
+
+impl fmt::Display for InterpreterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Int(i) => i.to_string(),
-            Self::Float(f) => f.to_string(),
-            Self::String(s) => s.to_string(),
-            Self::Bool(b) => b.to_string(),
-            Self::Array(a) => format!(
-                "[{}]",
-                a.iter()
-                    .map(|v| v.to_formatted_string())
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => write!(f, "{}", x),
+            Self::String(s) => write!(f, "{}", s),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Array(a) => format_array(self, f, |f| {
+                write!(
+                    f,
+                    "[{}]",
+                    a.borrow()
+                        .iter()
+                        .map(|v| v.to_formatted_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }),
+            Self::Dict(d) => write!(
+                f,
+                "{{{}}}",
+                d.iter()
+                    .map(|(k, v)| format!("{}: {}", k, v.to_formatted_string()))
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
-            Self::Void => "Void".to_string(),
+            Self::Void => write!(f, "Void"),
             Self::Function { name, params, .. } => {
-                format!("Function {{ name: {}, params: {:?} }}", name, params)
+                write!(f, "Function {{ name: {}, params: {:?} }}", name, params)
             }
-            Self::NativeFunction { name, .. } => format!("NativeFunction {{ name: {} }}", name),
+            Self::Compiled { function, .. } => write!(
+                f,
+                "Function {{ name: {}, params: {:?} }}",
+                function.name, function.params
+            ),
+            Self::NativeFunction { name, .. } => write!(f, "NativeFunction {{ name: {} }}", name),
+        }
+    }
+}
+
+/// Like a derived `Debug`, except that an array that contains itself is
+/// shown as `[...]` where it appears again.
+impl fmt::Debug for InterpreterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(i) => f.debug_tuple("Int").field(i).finish(),
+            Self::Float(x) => f.debug_tuple("Float").field(x).finish(),
+            Self::String(s) => f.debug_tuple("String").field(s).finish(),
+            Self::Bool(b) => f.debug_tuple("Bool").field(b).finish(),
+            Self::Array(a) => format_array(self, f, |f| f.debug_tuple("Array").field(a).finish()),
+            Self::Dict(d) => f.debug_tuple("Dict").field(d).finish(),
+            Self::Void => write!(f, "Void"),
+            Self::Function { name, params, body, env } => f
+                .debug_struct("Function")
+                .field("name", name)
+                .field("params", params)
+                .field("body", body)
+                .field("env", env)
+                .finish(),
+            Self::NativeFunction { name, body } => f
+                .debug_struct("NativeFunction")
+                .field("name", name)
+                .field("body", body)
+                .finish(),
+            Self::Compiled { function, env } => f
+                .debug_struct("Compiled")
+                .field("function", function)
+                .field("env", env)
+                .finish(),
         }
     }
 }
@@ -109,66 +481,328 @@ impl TryFrom<AstNode> for InterpreterValue {
                 for value in value.iter() {
                     array.push(Rc::new((value.clone()).try_into()?));
                 }
-                Ok(Self::Array(array))
+                Ok(Self::Array(RefCell::new(array)))
+            }
+            AstNodeType::Dict(entries) => {
+                let mut dict = BTreeMap::new();
+                for (key, value) in entries {
+                    let key_span = key.span;
+                    let key = DictKey::new(&key.try_into()?, key_span)?;
+                    dict.insert(key, Rc::new(value.try_into()?));
+                }
+                Ok(Self::Dict(dict))
             }
-            AstNodeType::Fn { name, params, body } => Ok(Self::Function {
-                name,
-                params: params
-                    .clone()
-                    .into_iter()
-                    .map(|p| match p {
-                        AstNode {
-                            ty: AstNodeType::Ident(i),
-                            ..
-                        } => i,
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-                body,
-            }),
             AstNodeType::Keyword(Keyword::True) => Ok(Self::Bool(true)),
             AstNodeType::Keyword(Keyword::False) => Ok(Self::Bool(false)),
             _ => Err(
-                InterpreterError::InvalidConstValue(value.clone(), value.line, value.col).into(),
+                InterpreterError::InvalidConstValue(value.span).into(),
             ),
         }
     }
 }
 
+/// A value that can be used as a dict key. Dicts are ordered by key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DictKey {
+    Int(i64),
+    String(String),
+    Bool(bool),
+}
+
+impl DictKey {
+    /// Converts a value to a key, failing at `span` if it can't be one.
+    pub fn new(value: &InterpreterValue, span: Span) -> Result<Self> {
+        match value {
+            InterpreterValue::Int(i) => Ok(Self::Int(*i)),
+            InterpreterValue::String(s) => Ok(Self::String(s.clone())),
+            InterpreterValue::Bool(b) => Ok(Self::Bool(*b)),
+            _ => Err(InterpreterError::InvalidDictKey(value.get_type().to_string(), span).into()),
+        }
+    }
+}
+
+impl From<&DictKey> for InterpreterValue {
+    fn from(key: &DictKey) -> Self {
+        match key {
+            DictKey::Int(i) => Self::Int(*i),
+            DictKey::String(s) => Self::String(s.clone()),
+            DictKey::Bool(b) => Self::Bool(*b),
+        }
+    }
+}
+
+impl fmt::Display for DictKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", InterpreterValue::from(self).to_formatted_string())
+    }
+}
+
+/// The outcome of executing a node. Anything other than `Value` unwinds to
+/// the closest enclosing loop or function call.
+#[derive(Debug, Clone)]
+pub(crate) enum ControlFlow {
+    Value(Rc<InterpreterValue>),
+    Return(Rc<InterpreterValue>),
+    Break,
+    Continue,
+    /// A call in tail position, to be made by the function call it is the
+    /// result of.
+    TailCall {
+        function: Rc<InterpreterValue>,
+        args: Vec<Rc<InterpreterValue>>,
+        span: Span,
+    },
+}
+
+impl ControlFlow {
+    fn keyword(&self) -> &'static str {
+        match self {
+            Self::Value(_) => "value",
+            Self::Return(_) | Self::TailCall { .. } => "return",
+            Self::Break => "break",
+            Self::Continue => "continue",
+        }
+    }
+
+    /// The error for a signal that reached a point it cannot pass through.
+    fn escape_error(&self, span: Span) -> InterpreterError {
+        InterpreterError::UnexpectedControlFlow(self.keyword().to_owned(), span)
+    }
+}
+
+/// Unwraps a `ControlFlow::Value`, or returns any other signal from the
+/// enclosing function.
+macro_rules! value {
+    ($flow:expr) => {
+        match $flow {
+            ControlFlow::Value(value) => value,
+            flow => return Ok(flow),
+        }
+    };
+}
+
+/// Settings for a run of a program, shared by every scope in it.
+#[derive(Debug, Default)]
+pub struct Context {
+    /// Whether natives may use files, stdin and the process.
+    pub allow_io: bool,
+    /// The arguments given after the program's path.
+    pub args: Vec<String>,
+    /// Whether to compile the program to bytecode and run it on the VM
+    /// instead of walking the AST.
+    pub vm: bool,
+    /// Whether to warn about `let` and `const` declarations that shadow a
+    /// variable of an outer scope.
+    pub warn_shadowing: bool,
+    /// Where warnings are written. Without it they are dropped.
+    pub warnings: Option<Output>,
+    /// Where `print` and `dbg` write to.
+    pub output: Output,
+    /// Pauses the program at breakpoints. Only the tree-walker supports it.
+    pub debugger: Option<Rc<RefCell<Debugger>>>,
+    /// Stops the program if it runs for too long or recurses too deeply.
+    pub limits: Limits,
+}
+
+/// A sink for a program's output, which is stdout unless it is captured.
+#[derive(Clone)]
+pub struct Output(pub Rc<RefCell<dyn Write>>);
+
+impl Output {
+    /// An output that collects everything written to it in the returned
+    /// buffer.
+    pub fn buffer() -> (Self, Rc<RefCell<Vec<u8>>>) {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        (Self(buffer.clone()), buffer)
+    }
+
+    /// An output to stderr.
+    pub fn stderr() -> Self {
+        Self(Rc::new(RefCell::new(io::stderr())))
+    }
+
+    /// Writes a line.
+    pub fn print(&self, line: fmt::Arguments) -> io::Result<()> {
+        writeln!(self.0.borrow_mut(), "{}", line)
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self(Rc::new(RefCell::new(io::stdout())))
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
 #[derive(Debug)]
 pub struct Interpreter {
     pub(crate) ast: Vec<AstNode>,
     pub(crate) top_scope: InterpreterScope,
+    pub(crate) modules: Rc<ModuleLoader>,
 }
 
 impl Interpreter {
-    fn find_constants(&mut self) -> Result<()> {
-        for node in self.ast.iter() {
+    /// Creates an interpreter for `ast` with the native functions defined in
+    /// its top scope. `sources` holds the files the AST was parsed from, and
+    /// any files it imports are added to it.
+    pub fn new(
+        ast: Vec<AstNode>,
+        functions: HashMap<String, NativeFn>,
+        sources: Rc<SourceMap>,
+        context: Context,
+    ) -> Self {
+        let modules = ModuleLoader::new(sources, functions, Rc::new(context));
+        Self::with_loader(ast, Rc::new(modules))
+    }
+
+    /// Creates an interpreter sharing a module loader, and so the loaded
+    /// modules, with other interpreters.
+    pub(crate) fn with_loader(ast: Vec<AstNode>, modules: Rc<ModuleLoader>) -> Self {
+        let top_scope = InterpreterScope::with_context(modules.context.clone());
+        // In order of name, so each native gets the same slot in every run.
+        let mut functions = modules.functions.borrow().clone().into_iter().collect::<Vec<_>>();
+        functions.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, function) in functions {
+            top_scope.env.set(
+                &name,
+                Rc::new(InterpreterValue::NativeFunction {
+                    name: name.clone(),
+                    body: function,
+                }),
+            );
+        }
+        Self {
+            ast,
+            top_scope,
+            modules,
+        }
+    }
+
+    /// Checks the names used by the program, then defines the top level
+    /// declarations and runs `main`.
+    pub fn run(mut self) -> Result<Rc<InterpreterValue>> {
+        self.top_scope.context.limits.start();
+        self.resolve()?;
+        self.find_constants()?;
+
+        let main = self.find_main()?;
+
+        self.evaluate_top_level(&main)
+    }
+
+    /// Reports any undefined names or wrong argument counts in the program
+    /// before it runs, and works out where each variable is stored.
+    pub(crate) fn resolve(&mut self) -> Result<()> {
+        let warnings = resolver::resolve_program(&mut self.ast, &self.top_scope.env)?;
+        self.warn(&warnings);
+        Ok(())
+    }
+
+    /// Writes the resolver's warnings to the context's warnings, if they were
+    /// asked for.
+    pub(crate) fn warn(&self, warnings: &[ResolveWarning]) {
+        let context = &self.modules.context;
+        let Some(output) = context.warnings.as_ref().filter(|_| context.warn_shadowing) else {
+            return;
+        };
+        for warning in warnings {
+            let warning = diagnostic::render_warning(warning, &self.modules.sources);
+            // A warning that can't be written shouldn't stop the program.
+            let _ = write!(output.0.borrow_mut(), "{}", warning);
+        }
+    }
+
+    /// Defines a native in the top scope, and in the modules imported from
+    /// then on.
+    pub(crate) fn define_native(&mut self, name: &str, function: NativeFn) {
+        let value = Rc::new(InterpreterValue::NativeFunction {
+            name: name.to_owned(),
+            body: function.clone(),
+        });
+        self.top_scope.env.set(name, value);
+        self.modules.functions.borrow_mut().insert(name.to_owned(), function);
+    }
+
+    pub(crate) fn context(&self) -> Rc<Context> {
+        self.top_scope.context.clone()
+    }
+
+    /// The top frame, holding the natives and everything defined at the top
+    /// level.
+    pub(crate) fn globals(&self) -> &Environment {
+        &self.top_scope.env
+    }
+
+    /// Evaluates a node directly in the top scope, as the REPL does. A `main`
+    /// block is run in place.
+    pub fn evaluate_top_level(&mut self, node: &AstNode) -> Result<Rc<InterpreterValue>> {
+        let node = match &node.ty {
+            AstNodeType::Main(body) => body,
+            AstNodeType::Import { path, alias } => {
+                self.import(path, alias, node.span)?;
+                return Ok(Rc::new(InterpreterValue::Void));
+            }
+            _ => node,
+        };
+        if self.top_scope.context.vm {
+            return vm::run(node, &self.top_scope.env, &self.top_scope.context);
+        }
+        match self.top_scope.execute(node)? {
+            ControlFlow::Value(value) | ControlFlow::Return(value) => Ok(value),
+            ControlFlow::TailCall {
+                function,
+                args,
+                span,
+            } => self.top_scope.call(&function, args, span),
+            flow => Err(flow.escape_error(node.span).into()),
+        }
+    }
+
+    /// Defines `alias.name` for everything the module at `path` defines.
+    fn import(&mut self, path: &str, alias: &str, span: Span) -> Result<()> {
+        let namespace = self.modules.load(path, span)?;
+        for (name, value) in namespace.iter() {
+            self.top_scope
+                .set(&format!("{}.{}", alias, name), value.clone())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn find_constants(&mut self) -> Result<()> {
+        let ast = std::mem::take(&mut self.ast);
+        let result = self.define_top_level(&ast);
+        self.ast = ast;
+        result
+    }
+
+    fn define_top_level(&mut self, ast: &[AstNode]) -> Result<()> {
+        for node in ast.iter() {
             match &node.ty {
-                AstNodeType::Const { name, value } => {
-                    self.top_scope
-                        .variables
-                        .insert(name.clone(), Rc::new((*value.clone()).try_into()?));
+                AstNodeType::Import { path, alias } => self.import(path, alias, node.span)?,
+                AstNodeType::Const { name, slot, value } => {
+                    let value = match &value.ty {
+                        AstNodeType::Fn {
+                            name, params, body, ..
+                        } => {
+                            self.top_scope.make_function(name.as_deref(), params, body)
+                        }
+                        _ => (**value).clone().try_into()?,
+                    };
+                    self.top_scope.env.define_constant(name, *slot, Rc::new(value));
                 }
-                AstNodeType::Fn { name, params, body } => {
-                    self.top_scope.variables.insert(
-                        name.clone(),
-                        Rc::new(InterpreterValue::Function {
-                            name: name.clone(),
-                            params: params
-                                .clone()
-                                .into_iter()
-                                .map(|p| match p {
-                                    AstNode {
-                                        ty: AstNodeType::Ident(i),
-                                        ..
-                                    } => i,
-                                    _ => unreachable!(),
-                                })
-                                .collect(),
-                            body: body.clone(),
-                        }),
-                    );
+                AstNodeType::Fn {
+                    name: Some(name),
+                    params,
+                    body,
+                    ..
+                } => {
+                    let function = self.top_scope.make_function(Some(name), params, body);
+                    self.top_scope.set(name, Rc::new(function))?;
                 }
                 _ => {}
             }
@@ -181,20 +815,14 @@ impl Interpreter {
         let mut main: Option<(AstNode, AstNode)> = None;
 
         for node in self.ast.iter() {
-            match &node.ty {
-                AstNodeType::Main(nodes) => {
-                    if main.is_some() {
-                        return Err(InterpreterError::MultipleMainFunctions(
-                            main.as_ref().unwrap().0.line,
-                            main.as_ref().unwrap().0.col,
-                            nodes.line,
-                            nodes.col,
-                        )
-                        .into());
-                    }
-                    main = Some((node.clone(), *nodes.clone()));
+            if let AstNodeType::Main(nodes) = &node.ty {
+                if let Some((first, _)) = &main {
+                    return Err(InterpreterError::MultipleMainFunctions(
+                        first.span, nodes.span,
+                    )
+                    .into());
                 }
-                _ => {}
+                main = Some((node.clone(), *nodes.clone()));
             }
         }
 
@@ -202,185 +830,475 @@ impl Interpreter {
     }
 }
 
-#[derive(Debug)]
+/// A frame of variables. Frames are reference counted so a function can keep
+/// the frame it was defined in alive after the call that created it returns.
+///
+/// Variables are stored by the index the resolver gave them. Only the top
+/// frame also knows its variables by name, for natives, imports and the REPL.
+#[derive(Default)]
+pub struct Environment {
+    parent: Option<Rc<Environment>>,
+    /// `None` for variables that have not been defined yet.
+    slots: RefCell<Vec<Option<Rc<InterpreterValue>>>>,
+    /// A `BTreeMap`, which unlike a `HashMap` costs nothing to create empty,
+    /// as it is in every frame but the top one.
+    names: RefCell<BTreeMap<String, usize>>,
+    /// The indices of the variables declared with `const`.
+    constants: RefCell<BTreeSet<usize>>,
+}
+
+impl Environment {
+    pub fn new_child(parent: &Rc<Environment>) -> Rc<Self> {
+        Self::with_values(parent, Vec::new())
+    }
+
+    /// Creates a child frame whose first variables are `values`, as a
+    /// function call's frame starts with its arguments.
+    pub fn with_values(parent: &Rc<Environment>, values: Vec<Rc<InterpreterValue>>) -> Rc<Self> {
+        Rc::new(Self {
+            parent: Some(parent.clone()),
+            slots: RefCell::new(values.into_iter().map(Some).collect()),
+            names: RefCell::new(BTreeMap::new()),
+            constants: RefCell::new(BTreeSet::new()),
+        })
+    }
+
+    /// Replaces the values of this frame with `values`, so a frame can be
+    /// reused instead of allocating a new one.
+    pub(crate) fn refill(&self, values: impl IntoIterator<Item = Rc<InterpreterValue>>) {
+        let mut slots = self.slots.borrow_mut();
+        slots.clear();
+        slots.extend(values.into_iter().map(Some));
+    }
+
+    pub fn parent(&self) -> Option<&Rc<Environment>> {
+        self.parent.as_ref()
+    }
+
+    fn top(&self) -> &Environment {
+        let mut env = self;
+        while let Some(parent) = env.parent.as_deref() {
+            env = parent;
+        }
+        env
+    }
+
+    fn frame(&self, depth: usize) -> &Environment {
+        let mut env = self;
+        for _ in 0..depth {
+            env = env.parent.as_deref().expect("resolved slot is out of scope");
+        }
+        env
+    }
+
+    /// The index of a variable of the top frame, adding a slot for it if it
+    /// has none yet.
+    pub fn index_of(&self, name: &str) -> usize {
+        let top = self.top();
+        let mut names = top.names.borrow_mut();
+        if let Some(index) = names.get(name) {
+            return *index;
+        }
+        let mut slots = top.slots.borrow_mut();
+        slots.push(None);
+        names.insert(name.to_owned(), slots.len() - 1);
+        slots.len() - 1
+    }
+
+    /// The frame and index `slot` refers to.
+    fn locate(&self, name: &str, slot: Slot) -> (&Environment, usize) {
+        match slot {
+            Slot::Unresolved => (self.top(), self.index_of(name)),
+            Slot::Global(index) => (self.top(), index),
+            Slot::Local { depth, index } => (self.frame(depth), index),
+        }
+    }
+
+    /// Looks up a variable of the top frame by name.
+    pub fn get(&self, name: &str) -> Option<Rc<InterpreterValue>> {
+        let top = self.top();
+        let index = *top.names.borrow().get(name)?;
+        top.slots.borrow()[index].clone()
+    }
+
+    /// Looks up a variable. `name` is only used if the variable hasn't been
+    /// resolved.
+    pub fn lookup(&self, name: &str, slot: Slot) -> Option<Rc<InterpreterValue>> {
+        let (env, index) = match slot {
+            Slot::Unresolved => return self.get(name),
+            Slot::Global(index) => (self.top(), index),
+            Slot::Local { depth, index } => (self.frame(depth), index),
+        };
+        env.slots.borrow().get(index).cloned().flatten()
+    }
+
+    /// The variables of this frame that have been defined, by name. Frames
+    /// other than the top one only know the names given to `record_name`.
+    pub fn variables(&self) -> Vec<(String, Rc<InterpreterValue>)> {
+        let slots = self.slots.borrow();
+        self.names
+            .borrow()
+            .iter()
+            .filter_map(|(name, &index)| Some((name.clone(), slots.get(index)?.clone()?)))
+            .collect()
+    }
+
+    /// Remembers the name of a resolved variable, which is otherwise only
+    /// known by its slot, so `variables` can list it.
+    pub(crate) fn record_name(&self, name: &str, slot: Slot) {
+        if let Slot::Local { depth, index } = slot {
+            self.frame(depth).names.borrow_mut().insert(name.to_owned(), index);
+        }
+    }
+
+    /// Defines a variable of the top frame by name.
+    pub fn set(&self, name: &str, value: Rc<InterpreterValue>) {
+        self.define(name, Slot::Unresolved, value);
+    }
+
+    /// Defines a variable, replacing any value it already has. Declaring a
+    /// constant again with `let` makes it a variable.
+    pub fn define(&self, name: &str, slot: Slot, value: Rc<InterpreterValue>) {
+        self.bind(name, slot, value, false);
+    }
+
+    /// Defines a variable that `set` can't overwrite.
+    pub fn define_constant(&self, name: &str, slot: Slot, value: Rc<InterpreterValue>) {
+        self.bind(name, slot, value, true);
+    }
+
+    fn bind(&self, name: &str, slot: Slot, value: Rc<InterpreterValue>, constant: bool) {
+        let (env, index) = self.locate(name, slot);
+        let mut slots = env.slots.borrow_mut();
+        if slots.len() <= index {
+            slots.resize(index + 1, None);
+        }
+        slots[index] = Some(value);
+        let mut constants = env.constants.borrow_mut();
+        if constant {
+            constants.insert(index);
+        } else {
+            constants.remove(&index);
+        }
+    }
+
+    /// Overwrites an existing variable, as `set` does. It is an error at
+    /// `span` if the variable hasn't been defined or is a constant.
+    pub fn replace(
+        &self,
+        name: &str,
+        slot: Slot,
+        value: Rc<InterpreterValue>,
+        span: Span,
+    ) -> Result<(), InterpreterError> {
+        let (env, index) = self.locate(name, slot);
+        if env.constants.borrow().contains(&index) {
+            return Err(InterpreterError::AssignToConstant(name.to_owned(), span));
+        }
+        match env.slots.borrow_mut().get_mut(index) {
+            Some(Some(current)) => {
+                *current = value;
+                Ok(())
+            }
+            _ => Err(InterpreterError::VariableNotFound(name.to_owned(), span)),
+        }
+    }
+}
+
+impl fmt::Debug for Environment {
+    /// Frames are not printed recursively: a function stored in the frame it
+    /// captured would otherwise print forever.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.names.borrow().keys().cloned().collect::<Vec<_>>();
+        f.debug_struct("Environment")
+            .field("names", &names)
+            .field("slots", &self.slots.borrow().len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct InterpreterScope {
-    pub(crate) parent: Option<*mut InterpreterScope>,
-    pub(crate) variables: HashMap<String, Rc<InterpreterValue>>,
+    pub(crate) env: Rc<Environment>,
+    pub(crate) context: Rc<Context>,
 }
 
-/// I know this is unsafe, but I'm not sure how to do it otherwise without
-/// making the code more complicated.
-fn g<'a>(parent: &*mut InterpreterScope) -> &'a mut InterpreterScope {
-    if parent.is_null() {
-        panic!("Parent is null");
+impl Default for InterpreterScope {
+    fn default() -> Self {
+        Self::new()
     }
-    unsafe { &mut **parent }
 }
 
 impl InterpreterScope {
     pub fn new() -> Self {
+        Self::with_context(Rc::default())
+    }
+
+    pub fn with_context(context: Rc<Context>) -> Self {
         Self {
-            parent: None,
-            variables: HashMap::new(),
+            env: Rc::new(Environment::default()),
+            context,
         }
     }
 
     pub fn new_child(&self) -> Self {
         Self {
-            parent: Some(self as *const InterpreterScope as *mut InterpreterScope),
-            variables: HashMap::new(),
+            env: Environment::new_child(&self.env),
+            context: self.context.clone(),
         }
     }
 
-    pub fn get(&self, name: &str, line: usize, col: usize) -> Result<Rc<InterpreterValue>> {
-        if let Some(value) = self.variables.get(name) {
-            return Ok(value.clone());
-        }
-
-        if let Some(parent) = self.parent.as_ref() {
-            return g(parent).get(name, line, col);
-        }
-
-        Err(InterpreterError::VariableNotFound(name.to_string(), line, col).into())
+    /// Looks up a variable of the top scope by name.
+    pub fn get(&self, name: &str, span: Span) -> Result<Rc<InterpreterValue>> {
+        self.env
+            .get(name)
+            .ok_or_else(|| InterpreterError::VariableNotFound(name.to_string(), span).into())
     }
 
+    /// Defines a variable of the top scope by name.
     pub fn set(&mut self, name: &str, value: Rc<InterpreterValue>) -> Result<()> {
-        self.variables.insert(name.to_string(), value);
+        self.env.set(name, value);
         Ok(())
     }
 
+    fn lookup(&self, name: &str, slot: Slot, span: Span) -> Result<Rc<InterpreterValue>> {
+        self.env
+            .lookup(name, slot)
+            .ok_or_else(|| InterpreterError::VariableNotFound(name.to_string(), span).into())
+    }
+
     pub fn replace(
         &mut self,
         name: &str,
+        slot: Slot,
         value: Rc<InterpreterValue>,
-        line: usize,
-        col: usize,
+        span: Span,
     ) -> Result<()> {
-        if self.variables.contains_key(name) {
-            self.variables.insert(name.to_string(), value);
-            return Ok(());
-        }
+        Ok(self.env.replace(name, slot, value, span)?)
+    }
 
-        if let Some(parent) = self.parent.as_ref() {
-            return g(parent).replace(name, value, line, col);
+    /// Defines a variable for a `let`, `const` or `fn`.
+    fn declare(&self, name: &str, slot: Slot, value: Rc<InterpreterValue>, constant: bool) {
+        if constant {
+            self.env.define_constant(name, slot, value);
+        } else {
+            self.env.define(name, slot, value);
+        }
+        if self.context.debugger.is_some() {
+            self.env.record_name(name, slot);
         }
-
-        Err(InterpreterError::VariableNotFound(name.to_string(), line, col).into())
     }
 
-    fn dbg_print_vars(&self) {
-        println!("Variables: {:#?}", self.variables);
-        if let Some(parent) = self.parent.as_ref() {
-            g(parent).dbg_print_vars();
+    /// Creates a function value that captures this scope's frame. It is
+    /// compiled if the program runs on the VM.
+    fn make_function(
+        &self,
+        name: Option<&str>,
+        params: &[AstNode],
+        body: &AstNode,
+    ) -> InterpreterValue {
+        let name = name.unwrap_or(ANONYMOUS_FUNCTION_NAME).to_owned();
+        let params = params
+            .iter()
+            .map(|p| match &p.ty {
+                AstNodeType::Ident(i, _) => i.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        if self.context.vm {
+            return InterpreterValue::Compiled {
+                function: Rc::new(compiler::compile_function(name, params, body)),
+                env: self.env.clone(),
+            };
+        }
+        InterpreterValue::Function {
+            name,
+            params,
+            body: Box::new(body.clone()),
+            env: self.env.clone(),
         }
     }
 
+    /// Evaluates a node to a value. A `return`, `break` or `continue` that
+    /// would escape this node is an error, since the caller (usually a native
+    /// function evaluating its arguments) has no way to pass it on.
     pub fn evaluate(&mut self, node: &AstNode) -> Result<Rc<InterpreterValue>> {
-        match &node.ty {
-            AstNodeType::Int(value) => Ok(Rc::new(InterpreterValue::Int(*value))),
-            AstNodeType::Float(value) => Ok(Rc::new(InterpreterValue::Float(*value))),
-            AstNodeType::String(value) => Ok(Rc::new(InterpreterValue::String(value.clone()))),
-            AstNodeType::Bool(b) => Ok(Rc::new(InterpreterValue::Bool(*b))),
+        match self.execute(node)? {
+            ControlFlow::Value(value) => Ok(value),
+            flow => Err(flow.escape_error(node.span).into()),
+        }
+    }
+
+    /// Evaluates a node, passing on any control flow signal it raises.
+    pub(crate) fn execute(&mut self, node: &AstNode) -> Result<ControlFlow> {
+        self.execute_node(node, false)
+    }
+
+    /// Evaluates a node. `tail` is whether the node's value is the result of
+    /// the function it is in, in which case a call to a user defined function
+    /// is left to the caller as a `TailCall`.
+    fn execute_node(&mut self, node: &AstNode, tail: bool) -> Result<ControlFlow> {
+        self.context.limits.step(node.span)?;
+        let value = match &node.ty {
+            AstNodeType::Int(value) => Rc::new(InterpreterValue::Int(*value)),
+            AstNodeType::Float(value) => Rc::new(InterpreterValue::Float(*value)),
+            AstNodeType::String(value) => Rc::new(InterpreterValue::String(value.clone())),
+            AstNodeType::Bool(b) => Rc::new(InterpreterValue::Bool(*b)),
             AstNodeType::Array(value) => {
                 let mut array = Vec::new();
                 for value in value.iter() {
-                    array.push(self.evaluate(value)?);
+                    array.push(value!(self.execute(value)?));
                 }
-                Ok(Rc::new(InterpreterValue::Array(array)))
+                Rc::new(InterpreterValue::Array(RefCell::new(array)))
             }
-            AstNodeType::Fn { name, params, body } => {
-                let function = Rc::new(InterpreterValue::Function {
-                    name: name.clone(),
-                    params: params
-                        .clone()
-                        .into_iter()
-                        .map(|p| match p.ty {
-                            AstNodeType::Ident(i) => i,
-                            _ => panic!(), // TODO: Better error handling
-                        })
-                        .collect(),
-                    body: body.clone(),
-                });
-                if !name.contains(" ") {
-                    // no spaces allowed in function names
-                    self.set(&name, function.clone())?;
+            AstNodeType::Dict(entries) => {
+                let mut dict = BTreeMap::new();
+                for (key, value) in entries {
+                    let key_value = value!(self.execute(key)?);
+                    let key = DictKey::new(&key_value, key.span)?;
+                    dict.insert(key, value!(self.execute(value)?));
+                }
+                Rc::new(InterpreterValue::Dict(dict))
+            }
+            AstNodeType::Fn {
+                name,
+                slot,
+                params,
+                body,
+            } => {
+                let function = Rc::new(self.make_function(name.as_deref(), params, body));
+                if let Some(name) = name {
+                    self.declare(name, *slot, function.clone(), false);
                 }
-                Ok(function)
+                function
             }
-            AstNodeType::Const { name, value } => {
-                // TODO: Allow for immutable variables
-                let value = self.evaluate(&value)?;
-                self.set(&name, value.clone())?;
-                Ok(value)
+            AstNodeType::Const { name, slot, value } => {
+                let value = value!(self.execute(value)?);
+                self.declare(name, *slot, value.clone(), true);
+                value
             }
-            AstNodeType::Let { name, value } => {
-                let value = self.evaluate(&value)?;
-                self.set(&name, value.clone())?;
-                Ok(value)
+            AstNodeType::Let { name, slot, value } => {
+                let value = value!(self.execute(value)?);
+                self.declare(name, *slot, value.clone(), false);
+                value
             }
-            AstNodeType::Set { name, value: node } => {
-                let value = self.evaluate(&node)?;
-                self.replace(&name, value.clone(), node.line, node.col)?;
-                Ok(value)
+            AstNodeType::Set { name, slot, value } => {
+                let value = value!(self.execute(value)?);
+                self.replace(name, *slot, value.clone(), node.span)?;
+                value
             }
             AstNodeType::If {
                 condition,
                 body,
                 else_body,
             } => {
-                let condition = self.evaluate(&condition)?;
-                let condition = match condition.as_ref() {
+                let value = value!(self.execute(condition)?);
+                let condition = match value.as_ref() {
                     InterpreterValue::Bool(b) => *b,
                     _ => {
                         return Err(InterpreterError::InvalidType1Native(
-                            condition.get_type().to_string(),
+                            value.get_type().to_string(),
                             "if".to_string(),
+                            condition.span,
                         )
                         .into());
                     }
                 };
                 if condition {
-                    self.evaluate(&body)
-                } else {
-                    match else_body {
-                        Some(else_body) => self.evaluate(&else_body),
-                        None => Ok(Rc::new(InterpreterValue::Void)),
-                    }
+                    return self.execute_node(body, tail);
+                }
+                match else_body {
+                    Some(else_body) => return self.execute_node(else_body, tail),
+                    None => Rc::new(InterpreterValue::Void),
                 }
             }
             AstNodeType::While { condition, body } => {
                 let mut result = Rc::new(InterpreterValue::Void);
                 loop {
-                    let condition = self.evaluate(&condition)?;
-                    let condition = match condition.as_ref() {
+                    let value = value!(self.execute(condition)?);
+                    let keep_going = match value.as_ref() {
                         InterpreterValue::Bool(b) => *b,
                         _ => {
                             return Err(InterpreterError::InvalidType1Native(
-                                condition.get_type().to_string(),
+                                value.get_type().to_string(),
                                 "while".to_string(),
+                                condition.span,
                             )
                             .into());
                         }
                     };
-                    if !condition {
-                        break Ok(result);
+                    if !keep_going {
+                        break result;
+                    }
+                    match self.execute(body)? {
+                        ControlFlow::Value(value) => result = value,
+                        ControlFlow::Break => break result,
+                        ControlFlow::Continue => {}
+                        flow @ (ControlFlow::Return(_) | ControlFlow::TailCall { .. }) => {
+                            return Ok(flow)
+                        }
+                    }
+                }
+            }
+            AstNodeType::For {
+                name,
+                iterable,
+                body,
+            } => {
+                let items: Box<dyn Iterator<Item = Rc<InterpreterValue>>> =
+                    match self.range_call(iterable)? {
+                        Some(range) => Box::new(range.map(|n| Rc::new(InterpreterValue::Int(n)))),
+                        None => {
+                            let value = value!(self.execute(iterable)?);
+                            Box::new(loop_items(&value, iterable.span)?.into_iter())
+                        }
+                    };
+                let mut result = Rc::new(InterpreterValue::Void);
+                for item in items {
+                    // Each iteration binds the item in a frame of its own, so
+                    // closures made in it keep that item.
+                    let mut scope = InterpreterScope {
+                        env: Environment::with_values(&self.env, vec![item]),
+                        context: self.context.clone(),
+                    };
+                    if self.context.debugger.is_some() {
+                        scope.env.record_name(name, Slot::Local { depth: 0, index: 0 });
+                    }
+                    match scope.execute(body)? {
+                        ControlFlow::Value(value) => result = value,
+                        ControlFlow::Break => break,
+                        ControlFlow::Continue => {}
+                        flow @ (ControlFlow::Return(_) | ControlFlow::TailCall { .. }) => {
+                            return Ok(flow)
+                        }
                     }
-                    result = self.evaluate(&body)?;
                 }
+                result
+            }
+            AstNodeType::Return(value) => {
+                // Whatever is returned is the function's result, so it is
+                // in tail position.
+                let value = match value {
+                    Some(value) => value!(self.execute_node(value, true)?),
+                    None => Rc::new(InterpreterValue::Void),
+                };
+                return Ok(ControlFlow::Return(value));
             }
+            AstNodeType::Break => return Ok(ControlFlow::Break),
+            AstNodeType::Continue => return Ok(ControlFlow::Continue),
             AstNodeType::Main(_) => {
-                Err(InterpreterError::MainInInnerScope(node.line, node.col).into())
-            }
-            AstNodeType::Call { name, params } => {
-                let function = self.get(&name, node.line, node.col);
-                let function = match function {
-                    Ok(function) => function,
-                    Err(_) => {
-                        self.dbg_print_vars();
+                return Err(InterpreterError::MainInInnerScope(node.span).into())
+            }
+            AstNodeType::Import { .. } => {
+                return Err(InterpreterError::ImportInInnerScope(node.span).into())
+            }
+            AstNodeType::Call { name, slot, params } => {
+                let function = match self.env.lookup(name, *slot) {
+                    Some(function) => function,
+                    None => {
                         return Err(InterpreterError::FunctionNotFound(
                             name.to_string(),
-                            node.line,
-                            node.col,
+                            node.span,
                         )
                         .into());
                     }
@@ -389,49 +1307,178 @@ impl InterpreterScope {
                     InterpreterValue::Function {
                         name,
                         params: fn_params,
-                        body,
+                        ..
                     } => {
                         if params.len() != fn_params.len() {
                             return Err(
-                                InterpreterError::InvalidFunctionCall(name.to_owned()).into()
+                                InterpreterError::InvalidFunctionCall(name.to_owned(), node.span).into()
                             );
                         }
-                        let mut scope = InterpreterScope::new_child(self);
-                        for (param, value) in fn_params.iter().zip(params.iter()) {
-                            let value = scope.evaluate(value)?;
-                            scope.set(param, value)?;
+                        let mut args = Vec::with_capacity(params.len());
+                        for param in params {
+                            args.push(value!(self.execute(param)?));
+                        }
+                        if tail {
+                            return Ok(ControlFlow::TailCall {
+                                function,
+                                args,
+                                span: node.span,
+                            });
                         }
-                        Ok(scope.evaluate(&body)?)
+                        self.call_function(function, args, node.span)?
+                    }
+                    InterpreterValue::NativeFunction { body, .. } => {
+                        body.call(self, params, node.span)?
                     }
-                    InterpreterValue::NativeFunction { body, .. } => body(self, params),
                     _ => {
-                        if params.len() != 0 {
+                        if !params.is_empty() {
                             return Err(
-                                InterpreterError::InvalidFunctionCall(name.to_owned()).into()
+                                InterpreterError::InvalidFunctionCall(name.to_owned(), node.span).into()
                             );
                         }
-                        return Ok(function);
+                        function
                     }
                 }
             }
             AstNodeType::Block(nodes) => {
-                let mut scope = InterpreterScope::new_child(self);
-                scope.evaluate_block(&nodes)
+                let mut scope = self.new_child();
+                return scope.execute_block(nodes, tail);
             }
-            AstNodeType::Ident(ident) => {
-                let value = self.get(&ident, node.line, node.col)?;
-                Ok(value)
+            AstNodeType::Ident(ident, slot) => self.lookup(ident, *slot, node.span)?,
+            AstNodeType::Keyword(keyword) => {
+                return Err(
+                    InterpreterError::UnexpectedKeyword(*keyword, node.span).into(),
+                )
             }
-            AstNodeType::Keyword(keyword) => todo!("{:?}", keyword),
+        };
+        Ok(ControlFlow::Value(value))
+    }
+
+    /// Calls a function value with arguments that have already been
+    /// evaluated, as natives taking callbacks do.
+    pub fn call(
+        &mut self,
+        function: &Rc<InterpreterValue>,
+        args: Vec<Rc<InterpreterValue>>,
+        span: Span,
+    ) -> Result<Rc<InterpreterValue>> {
+        match function.as_ref() {
+            InterpreterValue::Function { .. } => self.call_function(function.clone(), args, span),
+            InterpreterValue::Compiled { function, env } => {
+                vm::call(function, env, args, span, &self.context)
+            }
+            InterpreterValue::NativeFunction { body, .. } => {
+                // Natives evaluate their own arguments, so bind the values in
+                // a frame of their own and pass variables referring to them.
+                let params = argument_nodes(args.len(), span);
+                let mut scope = InterpreterScope {
+                    env: Environment::with_values(&self.env, args),
+                    context: self.context.clone(),
+                };
+                body.call(&mut scope, &params, span)
+            }
+            value => Err(InterpreterError::InvalidFunctionCall(value.to_string(), span).into()),
         }
     }
 
-    pub fn evaluate_block(&mut self, nodes: &[AstNode]) -> Result<Rc<InterpreterValue>> {
+    /// Runs a user defined function. Calls in tail position in its body come
+    /// back as `TailCall`s and are run by this loop instead of recursing, so
+    /// tail recursion (including mutual recursion) runs in constant stack.
+    /// Tracebacks show the calls left this way as far as `TailCalls` keeps
+    /// them.
+    fn call_function(
+        &mut self,
+        function: Rc<InterpreterValue>,
+        args: Vec<Rc<InterpreterValue>>,
+        span: Span,
+    ) -> Result<Rc<InterpreterValue>> {
+        self.context.limits.enter_call(span)?;
+        let result = match self.context.debugger.clone() {
+            Some(debugger) => {
+                debugger.borrow_mut().enter_call();
+                let result = self.run_function(function, args, span);
+                debugger.borrow_mut().leave_call();
+                result
+            }
+            None => self.run_function(function, args, span),
+        };
+        self.context.limits.leave_call();
+        result
+    }
+
+    fn run_function(
+        &mut self,
+        mut function: Rc<InterpreterValue>,
+        mut args: Vec<Rc<InterpreterValue>>,
+        mut span: Span,
+    ) -> Result<Rc<InterpreterValue>> {
+        let mut tail_calls: TailCalls<Rc<InterpreterValue>> = TailCalls::default();
+        loop {
+            let InterpreterValue::Function {
+                name,
+                params,
+                body,
+                env,
+            } = function.as_ref()
+            else {
+                return self.call(&function, args, span);
+            };
+            if args.len() != params.len() {
+                return Err(InterpreterError::InvalidFunctionCall(name.to_owned(), span).into());
+            }
+            // The body runs in a child of the frame the function captured,
+            // which starts with the arguments.
+            let mut scope = InterpreterScope {
+                env: Environment::with_values(env, args),
+                context: self.context.clone(),
+            };
+            if self.context.debugger.is_some() {
+                for (index, param) in params.iter().enumerate() {
+                    scope.env.record_name(param, Slot::Local { depth: 0, index });
+                }
+            }
+            let flow = match scope.execute_node(body, true) {
+                Ok(flow) => flow,
+                Err(e) => {
+                    let frame = StackFrame {
+                        name: name.clone(),
+                        span,
+                        left_out: 0,
+                    };
+                    let e = Traceback::push(e, frame);
+                    return Err(tail_calls.unwind(e, |function| match function.as_ref() {
+                        InterpreterValue::Function { name, .. } => name.as_str(),
+                        _ => unreachable!(),
+                    }));
+                }
+            };
+            (function, args, span) = match flow {
+                ControlFlow::Value(value) | ControlFlow::Return(value) => return Ok(value),
+                ControlFlow::TailCall {
+                    function: next,
+                    args,
+                    span: at,
+                } => {
+                    tail_calls.push(function, span);
+                    (next, args, at)
+                }
+                flow => return Err(flow.escape_error(span).into()),
+            };
+        }
+    }
+
+    /// Evaluates each node in order, stopping early if one of them raises a
+    /// control flow signal. The last node is in tail position if the block
+    /// is.
+    pub(crate) fn execute_block(&mut self, nodes: &[AstNode], tail: bool) -> Result<ControlFlow> {
         let mut result = Rc::new(InterpreterValue::Void);
-        for node in nodes.iter() {
-            result = self.evaluate(node)?;
+        for (i, node) in nodes.iter().enumerate() {
+            if let Some(debugger) = &self.context.debugger {
+                debugger.borrow_mut().before(self, node)?;
+            }
+            result = value!(self.execute_node(node, tail && i == nodes.len() - 1)?);
         }
-        Ok(result)
+        Ok(ControlFlow::Value(result))
     }
 
     pub fn evaluate_each(&mut self, nodes: &[AstNode]) -> Result<Vec<Rc<InterpreterValue>>> {
@@ -441,32 +1488,745 @@ impl InterpreterScope {
         }
         Ok(result)
     }
+
+    /// The ints of `node` if it is a call to the native `range`, which a
+    /// `for` loop can go through without making the array. The name may
+    /// refer to something else by the time it runs, as with `and` and `or`.
+    fn range_call(&mut self, node: &AstNode) -> Result<Option<Range>> {
+        let AstNodeType::Call { name, slot, params } = &node.ty else {
+            return Ok(None);
+        };
+        if name != "range" || params.is_empty() || params.len() > 3 {
+            return Ok(None);
+        }
+        match self.env.lookup(name, *slot).as_deref() {
+            Some(InterpreterValue::NativeFunction { name, .. }) if name == "range" => {}
+            _ => return Ok(None),
+        }
+        let params = self.evaluate_each(params)?;
+        Range::new(&params, node.span).map(Some)
+    }
+}
+
+/// The items a `for` loop over `value` goes through: the elements of an
+/// array as they are when the loop starts, or the characters of a string.
+pub(crate) fn loop_items(
+    value: &InterpreterValue,
+    span: Span,
+) -> Result<Vec<Rc<InterpreterValue>>> {
+    match value {
+        InterpreterValue::Array(array) => Ok(array.borrow().clone()),
+        InterpreterValue::String(string) => Ok(string
+            .chars()
+            .map(|c| Rc::new(InterpreterValue::String(c.to_string())))
+            .collect()),
+        value => Err(InterpreterError::InvalidType1Native(
+            value.get_type().to_string(),
+            "for".to_string(),
+            span,
+        )
+        .into()),
+    }
+}
+
+/// Variables referring to the `count` arguments of a native, which are bound
+/// in a frame of their own.
+pub(crate) fn argument_nodes(count: usize, span: Span) -> Vec<AstNode> {
+    (0..count)
+        .map(|index| AstNode {
+            ty: AstNodeType::Ident(
+                format!("<argument {}>", index),
+                Slot::Local { depth: 0, index },
+            ),
+            span,
+        })
+        .collect()
 }
 
+/// Runs a program. `sources` holds the files the AST was parsed from.
 pub fn interpret(
     ast: Vec<AstNode>,
     functions: HashMap<String, NativeFn>,
+    sources: Rc<SourceMap>,
+    context: Context,
 ) -> Result<Rc<InterpreterValue>> {
-    let mut interpreter = Interpreter {
-        ast,
-        top_scope: InterpreterScope::new(),
-    };
+    Interpreter::new(ast, functions, sources, context).run()
+}
 
-    for (name, function) in functions {
-        interpreter.top_scope.set(
-            &name,
-            Rc::new(InterpreterValue::NativeFunction {
-                name: name.clone(),
-                body: function,
-            }),
-        )?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{default_fns::default_native_functions, lexer::Lexer, parser::Parser};
+
+    fn try_run(input: &str) -> Result<Rc<InterpreterValue>> {
+        let mut parser = Parser::try_new(Lexer::new(input)).unwrap();
+        interpret(
+            parser.parse().unwrap(),
+            default_native_functions(),
+            Rc::default(),
+            Context::default(),
+        )
+    }
+
+    fn run(input: &str) -> Rc<InterpreterValue> {
+        try_run(input).unwrap()
+    }
+
+    #[test]
+    fn test_returned_closure_keeps_its_frame() {
+        let result = run(r#"
+            fn make_counter() {
+                let count 0
+                fn increment() {
+                    set count (+ count 1)
+                    count
+                }
+                increment
+            }
+
+            main {
+                let counter (make_counter)
+                let other (make_counter)
+                (counter)
+                (other)
+                (counter)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(2)));
+    }
+
+    #[test]
+    fn test_closures_in_arrays() {
+        let result = run(r#"
+            fn adder(n) {
+                fn add(x) {
+                    (+ x n)
+                }
+                add
+            }
+
+            main {
+                let adders [(adder 1) (adder 10)]
+                let add_ten (get adders 1)
+                (add_ten 5)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(15)));
+    }
+
+    #[test]
+    fn test_arguments_are_evaluated_in_caller_scope() {
+        let result = run(r#"
+            fn second(a, b) {
+                b
+            }
+
+            main {
+                let a 1
+                (second 2 a)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(1)));
+    }
+
+    #[test]
+    fn test_lambda_as_argument() {
+        let result = run(r#"
+            fn apply_twice(f, x) {
+                (f (f x))
+            }
+
+            main {
+                (apply_twice fn(x) { (* x 3) } 2)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(18)));
+    }
+
+    #[test]
+    fn test_lambda_does_not_bind_a_name() {
+        let result = run(r#"
+            main {
+                let double fn(x) (* x 2)
+                (double 4)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(8)));
+
+        let mut parser = Parser::try_new(Lexer::new("main { fn(x) x (x) }")).unwrap();
+        assert!(interpret(
+            parser.parse().unwrap(),
+            default_native_functions(),
+            Rc::default(),
+            Context::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_top_level_lambda_is_rejected() {
+        let mut parser = Parser::try_new(Lexer::new("fn(x) { x }")).unwrap();
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn test_return_leaves_function_early() {
+        let result = run(r#"
+            fn find(arr, target) {
+                let i 0
+                while true {
+                    if (== (get arr i) target) {
+                        return i
+                    }
+                    set i (+ i 1)
+                }
+            }
+
+            main {
+                (find [5 6 7 8] 7)
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(2)));
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let result = run(r#"
+            main {
+                let i 0
+                let sum 0
+                while true {
+                    set i (+ i 1)
+                    if (== i 3) {
+                        continue
+                    }
+                    if (== i 6) {
+                        break
+                    }
+                    set sum (+ sum i)
+                }
+                sum
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(12)));
+    }
+
+    #[test]
+    fn test_return_from_main() {
+        let result = run(r#"
+            main {
+                return 1
+                2
+            }
+        "#);
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(1)));
+    }
+
+    #[test]
+    fn test_misplaced_control_flow_is_rejected() {
+        for input in [
+            "fn f() { break }",
+            "main { continue }",
+            "const f fn() { while true { fn() { break } } }",
+            "main { while true { } } fn g() { } const x 1 return",
+        ] {
+            let mut parser = Parser::try_new(Lexer::new(input)).unwrap();
+            assert!(parser.parse().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_errors_carry_the_call_stack() {
+        let input = r#"
+            fn inner(x) {
+                (+ x "a")
+            }
+
+            fn outer(x) {
+                (inner x)
+            }
+
+            main {
+                (outer 1)
+            }
+        "#;
+        let mut parser = Parser::try_new(Lexer::new(input)).unwrap();
+        let error = interpret(
+            parser.parse().unwrap(),
+            default_native_functions(),
+            Rc::default(),
+            Context::default(),
+        )
+        .unwrap_err();
+        let traceback = error.downcast_ref::<Traceback>().unwrap();
+        assert!(matches!(
+            traceback.error.downcast_ref(),
+            Some(InterpreterError::InvalidTypeArgNative(..))
+        ));
+        let frames = traceback
+            .frames
+            .iter()
+            .map(|f| (f.name.as_str(), f.span.line))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, vec![("inner", 7), ("outer", 11)]);
+    }
+
+    #[test]
+    fn test_dicts() {
+        let result = run(r#"
+            const EMPTY #{}
+
+            main {
+                let d #{"a" 1, "b" 2}
+                set d (dict_set (remove d "a") "c" (+ (dict_get d "a") 10))
+                [d (keys d) (values d) (has_key d "a") (dict_get d "a" 0) EMPTY]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            r#"[{"b": 2, "c": 11}, ["b", "c"], [2, 11], false, 0, {}]"#
+        );
     }
 
-    interpreter.find_constants()?;
+    #[test]
+    fn test_dict_errors() {
+        for input in [
+            r#"main { #{[1] 2} }"#,
+            r#"main { (dict_get #{"a" 1} "b") }"#,
+            r#"main { (keys [1 2]) }"#,
+        ] {
+            assert!(try_run(input).is_err(), "{}", input);
+        }
+
+        let mut parser = Parser::try_new(Lexer::new(r#"main { #{"a" 1 "b"} }"#)).unwrap();
+        assert!(parser.parse().is_err());
+    }
 
-    let main = interpreter.find_main()?;
+    #[test]
+    fn test_arrays_are_shared_and_mutable() {
+        let result = run(r#"
+            fn fill(arr, n) {
+                let i 0
+                while (!= i n) {
+                    (push arr i)
+                    set i (+ i 1)
+                }
+            }
 
-    let result = interpreter.top_scope.evaluate(&main)?;
+            main {
+                let a []
+                let b a
+                (fill a 4)
+                (set_at b 0 "x")
+                (insert_at b 4 "end")
+                let removed (remove_at a 1)
+                [a (len a) removed (pop b) (slice a 1) (concat a [9])]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            r#"[["x", 2, 3], 4, 1, "end", [2, 3], ["x", 2, 3, 9]]"#
+        );
+    }
 
-    Ok(result)
+    #[test]
+    fn test_arrays_that_contain_themselves() {
+        let result = run(r#"
+            main {
+                let a [1]
+                (push a a)
+                let b [1]
+                (push b [1 b])
+                [a (join a " ") (string #{"a" a}) (== a b) (== a [1 [1 a]]) (== a [1 [2]])]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            r#"[[1, [...]], "1 [1, [...]]", "{"a": [1, [...]]}", true, true, false]"#
+        );
+        assert!(format!("{:?}", result).contains("Array(RefCell { value: [Int(1), [...]] })"));
+    }
+
+    #[test]
+    fn test_index_out_of_range() {
+        for input in [
+            "main { (get [1 2] 2) }",
+            "main { (set_at [1 2] -1 0) }",
+            "main { (pop []) }",
+            "main { (slice [1 2] 0 3) }",
+        ] {
+            let error = try_run(input).unwrap_err();
+            assert!(
+                matches!(error.downcast_ref(), Some(InterpreterError::IndexOutOfRange(..))),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let result = run(r#"
+            main {
+                [(/ 7 2) (/ -7 2) (/ 7.0 2) (+ 1 2.5) (* 2 1.5 2) (- 1.5 1) (+ 1 2 3)]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[3, -3, 3.5, 3.5, 6, 0.5, 6]");
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        for (input, column) in [
+            ("main { (+ 9223372036854775807 1) }", 8),
+            ("main { (* 2 3 9223372036854775807) }", 8),
+            ("main { (- 1 (- 0 9223372036854775807) 3) }", 8),
+            ("main { (/ (- 0 9223372036854775807 1) -1) }", 8),
+            ("main { (/ 1 (/ 1 0)) }", 13),
+            ("main { (/ 1.5 0) }", 8),
+            ("main { (/ 1 0.0) }", 8),
+        ] {
+            let error = try_run(input).unwrap_err();
+            let span = match error.downcast_ref() {
+                Some(InterpreterError::IntegerOverflow(_, span))
+                | Some(InterpreterError::DivisionByZero(span)) => *span,
+                _ => panic!("{}: {:?}", input, error),
+            };
+            assert_eq!(span.col, column, "{}", input);
+        }
+        assert!(try_run(r#"main { (+ 1 "a") }"#).is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let result = run(r#"
+            main {
+                [(int "-42") (float "4.5") (int 2.9) (float 2) (int true)]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[-42, 4.5, 2, 2, 1]");
+
+        for (input, expected) in [
+            (r#"main { (int "abc") }"#, ("abc", "int")),
+            (r#"main { (int "1.5") }"#, ("1.5", "int")),
+            (r#"main { (float "x") }"#, ("x", "float")),
+        ] {
+            let error = try_run(input).unwrap_err();
+            match error.downcast_ref() {
+                Some(InterpreterError::InvalidNumber(string, ty, span)) => {
+                    assert_eq!((string.as_str(), ty.as_str()), expected);
+                    assert_eq!((span.line, span.col), (1, 8), "{}", input);
+                }
+                _ => panic!("{}: {:?}", input, error),
+            }
+        }
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let result = run(r#"
+            main {
+                [(< 1 2) (<= 2 2) (> 1 2.5) (>= 2.5 2) (< "apple" "banana") (not (< 1 2))]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[true, true, false, true, true, false]");
+
+        for input in [
+            r#"main { (< 1 "a") }"#,
+            "main { (> [1] [2]) }",
+            "main { (not 1) }",
+            "main { (and (== 1 1) 1) }",
+            "main { (or) }",
+        ] {
+            assert!(try_run(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_and_or_short_circuit() {
+        let result = run(r#"
+            main {
+                let x 0
+                [(and (!= x 0) (== (/ 10 x) 2)) (or (== x 0) (== (/ 10 x) 2)) (and (< x 1))]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[false, true, true]");
+        assert!(try_run("main { (or (== 1 2) (/ 10 0)) }").is_err());
+    }
+
+    #[test]
+    fn test_structural_equality() {
+        let result = run(r#"
+            main {
+                let a [1 [2 "x"] #{"k" [3]}]
+                (push a a)
+                [
+                    (== [1 [2 "x"]] [1 [2 "x"]])
+                    (== [1 2] [1 2 3])
+                    (!= [1 2] [1 3])
+                    (== #{"k" [1]} #{"k" [1]})
+                    (== #{"k" 1} #{"j" 1})
+                    (== 1 1.0)
+                    (== a a)
+                ]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[true, false, true, true, false, true, true]");
+
+        for input in [r#"main { (== 1 "1") }"#, r#"main { (== [1] ["1"]) }"#] {
+            let error = try_run(input).unwrap_err();
+            assert!(
+                matches!(error.downcast_ref(), Some(InterpreterError::InvalidType2Native(..))),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_higher_order_functions() {
+        let result = run(r#"
+            fn square(x) { (* x x) }
+
+            main {
+                let xs (range 1 5)
+                [
+                    (map xs square)
+                    (map xs string)
+                    (filter xs fn(x) { (!= x 2) })
+                    (reduce xs + 0)
+                    (any xs fn(x) { (== x 3) })
+                    (all xs fn(x) { (== x 3) })
+                    (sort_by [3 1 2 1] fn(a, b) { (- b a) })
+                    (range 6 0 -2)
+                ]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            "[[1, 4, 9, 16], [\"1\", \"2\", \"3\", \"4\"], [1, 3, 4], 10, true, false, \
+             [3, 2, 1, 1], [6, 4, 2]]"
+        );
+    }
+
+    #[test]
+    fn test_range_near_the_ends_of_ints() {
+        let result = run(r#"
+            main {
+                let max 9223372036854775807
+                let min (- 0 max 1)
+                [
+                    (range (- max 2) max)
+                    (range (- max 3) max 2)
+                    (range (- max 1) max max)
+                    (range (+ min 2) min -1)
+                    (range (+ min 3) min -2)
+                    (range (+ min 1) min min)
+                ]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            "[[9223372036854775805, 9223372036854775806], \
+             [9223372036854775804, 9223372036854775806], [9223372036854775806], \
+             [-9223372036854775806, -9223372036854775807], \
+             [-9223372036854775805, -9223372036854775807], [-9223372036854775807]]"
+        );
+    }
+
+    #[test]
+    fn test_callback_errors() {
+        for input in [
+            "main { (filter [1] fn(x) { x }) }",
+            "main { (sort_by [1 2] fn(a, b) { true }) }",
+            "main { (map [1] 2) }",
+            "main { (map [1] fn(a, b) { a }) }",
+            "main { (range 0 5 0) }",
+        ] {
+            assert!(try_run(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let result = run(r#"
+            main {
+                let s (trim "  héllo, wörld ")
+                [
+                    (len s)
+                    (split s ", ")
+                    (join [1 "a"] "-")
+                    (substr s 1 4)
+                    (index_of s "wö")
+                    (replace s "l" "L")
+                    (upper s)
+                    (chars "añ")
+                    (starts_with s "hé")
+                    (format "{}{{}}{}" 1 "b")
+                ]
+            }
+        "#);
+        assert_eq!(
+            result.to_string(),
+            "[12, [\"héllo\", \"wörld\"], \"1-a\", \"éll\", 7, \"héLLo, wörLd\", \
+             \"HÉLLO, WÖRLD\", [\"a\", \"ñ\"], true, \"1{}b\"]"
+        );
+    }
+
+    #[test]
+    fn test_string_function_errors() {
+        for input in [
+            "main { (upper 1) }",
+            "main { (substr \"abc\" 2 4) }",
+            "main { (format \"{} {}\" 1) }",
+            "main { (format \"{}\" 1 2) }",
+        ] {
+            assert!(try_run(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_io_needs_permission() {
+        let error = try_run(r#"main { (read_file "test.thing") }"#).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(InterpreterError::IoNotAllowed(name, _)) if name == "read_file"
+        ));
+    }
+
+    #[test]
+    fn test_io_functions() {
+        let path = std::env::temp_dir().join(format!("ilt1-io-{}.txt", std::process::id()));
+        let input = format!(
+            r#"
+            fn fail() {{ (exit (len (args))) }}
+
+            main {{
+                (write_file "{0}" "a")
+                (append_file "{0}" (get (args) 0))
+                if (== (read_file "{0}") "ab") {{
+                    (fail)
+                }}
+            }}
+            "#,
+            path.display()
+        );
+        let mut parser = Parser::try_new(Lexer::new(&input)).unwrap();
+        let context = Context {
+            allow_io: true,
+            args: vec!["b".to_owned(), "c".to_owned()],
+            ..Default::default()
+        };
+        let result = interpret(
+            parser.parse().unwrap(),
+            default_native_functions(),
+            Rc::default(),
+            context,
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(Exit::code(&result.unwrap_err()), Some(2));
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_stack() {
+        let result = run(r#"
+            fn count(n, acc) {
+                if (== n 0) { acc } else { (count (- n 1) (+ acc 1)) }
+            }
+
+            fn is_even(n) {
+                if (== n 0) { true } else { (is_odd (- n 1)) }
+            }
+
+            fn is_odd(n) {
+                while true {
+                    if (== n 0) { return false }
+                    return (is_even (- n 1))
+                }
+            }
+
+            main {
+                [(count 100000 0) (is_even 100001)]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[100000, false]");
+    }
+
+    #[test]
+    fn test_natives_get_the_same_slots_in_every_run() {
+        // Each map of natives iterates in an order of its own.
+        let slots = || {
+            let interpreter = Interpreter::new(
+                Vec::new(),
+                default_native_functions(),
+                Rc::default(),
+                Context::default(),
+            );
+            ["+", "len", "print", "range"].map(|name| interpreter.top_scope.env.index_of(name))
+        };
+        let expected = slots();
+        for _ in 0..10 {
+            assert_eq!(slots(), expected);
+        }
+    }
+
+    #[test]
+    fn test_variables_in_nested_frames() {
+        let result = run(r#"
+            fn shadow(a, b) {
+                let a (+ a b)
+                a
+            }
+
+            main {
+                let x 1
+                fn get_x() { x }
+                let before (get_x)
+                let x 2
+                let outer 10
+                if true {
+                    let outer (+ outer 1)
+                    set x (+ x outer)
+                }
+                fn read_later() { later }
+                let later 5
+                let add_outer fn(n) { (+ n outer) }
+                [(shadow 2 3) before (get_x) x (read_later) (map [1 2] add_outer)]
+            }
+        "#);
+        assert_eq!(result.to_string(), "[5, 1, 13, 13, 5, [11, 12]]");
+
+        let error = try_run("main { fn f() { y } (f) let y 1 }").unwrap_err();
+        let traceback = error.downcast_ref::<Traceback>().unwrap();
+        assert!(matches!(
+            traceback.error.downcast_ref(),
+            Some(InterpreterError::VariableNotFound(name, _)) if name == "y"
+        ));
+    }
+
+    #[test]
+    fn test_constants_cannot_be_set() {
+        for (input, name) in [
+            ("const LIMIT 1 main { set LIMIT 2 }", "LIMIT"),
+            ("main { if true { const x 1 set x 2 } }", "x"),
+            ("main { const x 1 fn f() { set x 2 } (f) }", "x"),
+            ("const g fn() { 1 } main { set g 2 }", "g"),
+        ] {
+            let mut error = try_run(input).unwrap_err();
+            if let Some(traceback) = error.downcast_ref::<Traceback>() {
+                error = traceback.error.downcast_ref::<InterpreterError>().unwrap().clone().into();
+            }
+            match error.downcast_ref() {
+                Some(InterpreterError::AssignToConstant(found, span)) => {
+                    assert_eq!(found, name, "{}", input);
+                    let set = format!("set {} 2", name);
+                    assert_eq!(&input[span.offset..span.offset + span.len], set, "{}", input);
+                }
+                _ => panic!("{}: {}", input, error),
+            }
+        }
+
+        // Declaring the name again makes it a variable.
+        let result = run("main { const x 1 let x 2 set x 3 x }");
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(3)));
+    }
 }
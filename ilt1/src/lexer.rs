@@ -5,16 +5,32 @@ use std::{
 };
 use thiserror::Error;
 
+use crate::diagnostic::{Diagnostic, Span};
 use crate::token::{Token, TokenType};
 
 const DELIMITERS: [char; 7] = [',', '(', ')', '{', '}', '[', ']'];
 
 #[derive(Debug, PartialEq, Clone, Error)]
 pub enum LexError {
-    #[error("Unexpected character: {0} at {1}:{2}")]
-    UnexpectedChar(char, usize, usize),
+    #[error("Unexpected character: {0}")]
+    UnexpectedChar(char, Span),
     #[error("Unexpected end of file")]
-    UnexpectedEOF,
+    UnexpectedEOF(Span),
+}
+
+impl Diagnostic for LexError {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::UnexpectedChar(_, span) | Self::UnexpectedEOF(span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::UnexpectedChar(..) => None,
+            Self::UnexpectedEOF(_) => Some("this string is never closed"),
+        }
+    }
 }
 
 /// Lexes a string into tokens.
@@ -24,6 +40,14 @@ pub struct Lexer<'a> {
     current: Option<char>,
     line: usize,
     col: usize,
+    /// The index of the current character, or the length of the input once
+    /// it has all been read.
+    offset: usize,
+    len: usize,
+    /// Where the token being lexed started.
+    start: Span,
+    /// The index of the input in the `SourceMap`.
+    file: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -34,22 +58,49 @@ impl<'a> Lexer<'a> {
             current: None,
             line: 1,
             col: 0,
+            offset: 0,
+            len: input.chars().count(),
+            start: Span::default(),
+            file: 0,
+        }
+    }
+
+    /// Marks the tokens as coming from `file` in the `SourceMap`.
+    pub fn with_file(mut self, file: usize) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// The span of the current character.
+    fn current_span(&self) -> Span {
+        Span {
+            file: self.file,
+            line: self.line,
+            col: self.col,
+            offset: self.offset,
+            len: 1,
         }
     }
 
-    /// Creates a new Token from a TokenType
+    /// Creates a new Token from a TokenType. The token spans from where it
+    /// started up to the current character.
     fn new_token(&self, ty: TokenType) -> Token {
         Token {
             ty,
-            line: self.line,
-            col: self.col,
+            span: Span {
+                len: self.offset - self.start.offset,
+                ..self.start
+            },
         }
     }
 
-    /// Creates a new UnexpectedChar error with the current line and column.
-    /// Assumes the current character is Some.
+    /// Creates a new UnexpectedChar error at the current character, or an
+    /// UnexpectedEOF error if the input has run out.
     fn unexpected_char(&self) -> LexError {
-        LexError::UnexpectedChar(self.current.unwrap(), self.line, self.col)
+        match self.current {
+            Some(c) => LexError::UnexpectedChar(c, self.current_span()),
+            None => LexError::UnexpectedEOF(self.start),
+        }
     }
 
     /// Gets the current character in the input. Consumes the character if the
@@ -64,13 +115,19 @@ impl<'a> Lexer<'a> {
     /// Gets the next character in the input. Consumes the character. Increments
     /// the line and column numbers accordingly. Assumes newlines are \n.
     fn next_char(&mut self) -> Option<char> {
-        let (_, char) = match self.chars.next() {
+        let (offset, char) = match self.chars.next() {
             Some(it) => it,
             None => {
+                // The end of the input sits just after the last character.
+                if self.offset < self.len {
+                    self.col += 1;
+                }
                 self.current = None;
+                self.offset = self.len;
                 return None;
             }
         };
+        self.offset = offset;
         if char == '\n' {
             self.line += 1;
             self.col = 0;
@@ -94,16 +151,23 @@ impl<'a> Lexer<'a> {
                                         break c;
                                     }
                                 }
-                                None => return Ok(self.new_token(TokenType::Eof)),
+                                None => {
+                                    self.start = self.current_span();
+                                    return Ok(self.new_token(TokenType::Eof));
+                                }
                             }
                         }
                     } else {
                         c
                     }
                 }
-                None => return Ok(self.new_token(TokenType::Eof)),
+                None => {
+                    self.start = self.current_span();
+                    return Ok(self.new_token(TokenType::Eof));
+                }
             }
         };
+        self.start = self.current_span();
 
         let token = match c {
             c if DELIMITERS.contains(&c) => {
@@ -119,16 +183,21 @@ impl<'a> Lexer<'a> {
                     _ => unreachable!(),
                 }
             }
+            '#' if matches!(self.chars.peek(), Some((_, '{'))) => {
+                self.next_char();
+                self.next_char();
+                self.new_token(TokenType::HashLBrace)
+            }
             '"' => self.parse_string()?,
-            c if c.is_digit(10) || c == '.' => self.parse_number().ok_or(self.unexpected_char())?,
-            c if c == '-' => {
+            c if c.is_ascii_digit() || c == '.' => self.parse_number().ok_or_else(|| self.unexpected_char())?,
+            '-' => {
                 let next = self.chars.peek();
                 if next.is_none() {
                     return Err(self.unexpected_char().into());
                 }
                 let (_, next) = next.unwrap();
-                if next.is_digit(10) || next == &'.' {
-                    self.parse_number().ok_or(self.unexpected_char())?
+                if next.is_ascii_digit() || next == &'.' {
+                    self.parse_number().ok_or_else(|| self.unexpected_char())?
                 } else {
                     self.parse_ident()?
                 }
@@ -151,7 +220,7 @@ impl<'a> Lexer<'a> {
                     break;
                 }
                 Some('\\') => {
-                    let c = self.next_char().ok_or(LexError::UnexpectedEOF)?;
+                    let c = self.next_char().ok_or(LexError::UnexpectedEOF(self.start))?;
                     string.push(match c {
                         '"' => '"',
                         '\\' => '\\',
@@ -161,15 +230,13 @@ impl<'a> Lexer<'a> {
                         'n' => '\n',
                         'r' => '\r',
                         't' => '\t',
-                        '\n' => {
-                            self.line += 1;
-                            self.col = 0;
-                            continue;
-                        }
+                        // next_char already moved on to the next line
+                        '\n' => continue,
                         'u' => {
                             let mut hex = String::new();
                             for _ in 0..4 {
-                                let c = self.next_char().ok_or(LexError::UnexpectedEOF)?;
+                                let c =
+                                    self.next_char().ok_or(LexError::UnexpectedEOF(self.start))?;
                                 hex.push(c);
                             }
                             u32::from_str_radix(&hex, 16)
@@ -181,7 +248,7 @@ impl<'a> Lexer<'a> {
                     });
                 }
                 Some(c) => string.push(c),
-                None => return Err(LexError::UnexpectedEOF.into()),
+                None => return Err(LexError::UnexpectedEOF(self.start).into()),
             }
         }
 
@@ -198,7 +265,7 @@ impl<'a> Lexer<'a> {
 
         loop {
             if let Some(c) = self.next_char() {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     number.push(c);
                     continue;
                 } else if c == '.' {
@@ -254,17 +321,17 @@ impl<'a> Lexer<'a> {
         Ok(self.new_token(TokenType::new_ident(ident.as_str())))
     }
 
-    /// Parse all tokens from the input.
+    /// Parse all tokens from the input. The last token is always an Eof
+    /// token marking where the input ends.
     pub fn parse(mut self) -> Result<Vec<Token>> {
         let mut tokens = Vec::new();
 
         loop {
-            match self.next_token() {
-                Ok(Token {
-                    ty: TokenType::Eof, ..
-                }) => break,
-                Ok(token) => tokens.push(token),
-                Err(e) => return Err(e.into()),
+            let token = self.next_token()?;
+            let eof = token.ty == TokenType::Eof;
+            tokens.push(token);
+            if eof {
+                break;
             }
         }
 
@@ -275,6 +342,7 @@ impl<'a> Lexer<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::token::Keyword;
 
     #[test]
     fn test_parse_string() {
@@ -400,9 +468,9 @@ mod tests {
         assert_eq!(next(&mut lexer), TokenType::Comma);
         assert_eq!(next(&mut lexer), TokenType::Float(1234.5678));
         assert_eq!(next(&mut lexer), TokenType::Comma);
-        assert_eq!(next(&mut lexer), TokenType::Ident("true".to_string()));
+        assert_eq!(next(&mut lexer), TokenType::Keyword(Keyword::True));
         assert_eq!(next(&mut lexer), TokenType::Comma);
-        assert_eq!(next(&mut lexer), TokenType::Ident("false".to_string()));
+        assert_eq!(next(&mut lexer), TokenType::Keyword(Keyword::False));
         assert_eq!(next(&mut lexer), TokenType::Comma);
         assert_eq!(next(&mut lexer), TokenType::Ident("null".to_string()));
         assert_eq!(next(&mut lexer), TokenType::RBracket);
@@ -433,7 +501,7 @@ mod tests {
 
     #[test]
     fn test_large_input() {
-        let input = std::iter::repeat("a").take(1000000).collect::<String>();
+        let input = std::iter::repeat_n("a", 1000000).collect::<String>();
         let mut lexer = Lexer::new(&input);
         assert_eq!(
             lexer.next_token().unwrap().ty,
@@ -513,4 +581,31 @@ mod tests {
             TokenType::RBracket
         );
     }
+
+    #[test]
+    fn test_token_spans() {
+        let mut lexer = Lexer::new("(print\n  \"hi\" 12)");
+        let mut spans = Vec::new();
+        loop {
+            let token = lexer.next_token().unwrap();
+            spans.push((token.span.line, token.span.col, token.span.len));
+            if token.ty == TokenType::Eof {
+                break;
+            }
+        }
+        assert_eq!(
+            spans,
+            vec![(1, 1, 1), (1, 2, 5), (2, 3, 4), (2, 8, 2), (2, 10, 1), (2, 11, 0)]
+        );
+    }
+
+    #[test]
+    fn test_dict_open() {
+        let mut lexer = Lexer::new("#{\"a\" 1} #a");
+        assert_eq!(lexer.next_token().unwrap().ty, TokenType::HashLBrace);
+        assert_eq!(lexer.next_token().unwrap().ty, TokenType::String("a".to_string()));
+        assert_eq!(lexer.next_token().unwrap().ty, TokenType::Int(1));
+        assert_eq!(lexer.next_token().unwrap().ty, TokenType::RBrace);
+        assert_eq!(lexer.next_token().unwrap().ty, TokenType::Ident("#a".to_string()));
+    }
 }
@@ -0,0 +1,26 @@
+//! An interpreted language with prefix calls and C-like blocks. Programs can
+//! be run with `interpreter::interpret`, or embedded in a Rust program with
+//! an `Engine`, which can call functions of the program and give it natives
+//! written in Rust.
+
+pub mod ast;
+mod compiler;
+pub mod debugger;
+pub mod default_fns;
+pub mod diagnostic;
+mod engine;
+pub mod formatter;
+pub mod interpreter;
+pub mod lexer;
+pub mod limits;
+pub mod module;
+pub mod parser;
+pub mod repl;
+mod resolver;
+pub mod test_runner;
+pub mod token;
+mod vm;
+
+pub use engine::{Engine, EngineError, FromValue, IntoArgs, IntoValue, NativeResult, RegisterFn};
+pub use interpreter::{Context, InterpreterError, InterpreterValue, NativeFn, Output};
+pub use limits::Limits;
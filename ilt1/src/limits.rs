@@ -0,0 +1,140 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use crate::diagnostic::Span;
+use crate::interpreter::InterpreterError;
+
+/// How many steps are taken between looks at the clock, which costs more
+/// than a step.
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// How many calls can be in progress at once unless `with_max_depth` says
+/// otherwise. Each call in the tree-walker takes about 4 KB of native stack
+/// in a release build and many times more in a debug build, so this fits
+/// the 8 MB main thread of most platforms in a release build, and a thread
+/// with `STACK_SIZE` in either.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// The size of the stack the command line runs programs with, so that deep
+/// recursion stops at the call depth limit instead of overflowing the stack.
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Limits on a run of a program, for programs that can't be trusted to
+/// finish. Fuel and time are unlimited unless they are set with the `with_`
+/// methods, and calls are limited to `DEFAULT_MAX_DEPTH`.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let limits = ilt1::Limits::default()
+///     .with_fuel(1_000_000)
+///     .with_max_depth(200)
+///     .with_timeout(Duration::from_secs(1));
+/// let mut engine = ilt1::Engine::with_context(ilt1::Context { limits, ..Default::default() });
+/// assert!(engine.eval_str::<()>("while true {}").is_err());
+/// ```
+#[derive(Debug)]
+pub struct Limits {
+    fuel: Option<u64>,
+    max_depth: usize,
+    timeout: Option<Duration>,
+    /// The steps taken since the run started.
+    steps: Cell<u64>,
+    /// How many calls are in progress.
+    depth: Cell<usize>,
+    started: Cell<Option<Instant>>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            timeout: None,
+            steps: Cell::default(),
+            depth: Cell::default(),
+            started: Cell::default(),
+        }
+    }
+}
+
+impl Limits {
+    /// Limits a run to `fuel` steps. The tree-walker takes a step for each
+    /// node it evaluates and the VM for each instruction it runs, so the
+    /// same program can use different amounts on each. Natives that make or
+    /// go through arrays and strings take a step for each element.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Limits how many calls to functions of the program can be in progress
+    /// at once. Tail calls replace the call they are made from, so they
+    /// don't count. Deep recursion in the tree-walker overflows the stack
+    /// if the limit is too high for it (see `DEFAULT_MAX_DEPTH`).
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Limits how long a run can take. The clock is only checked between
+    /// steps, so a slow native call can go over.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Starts a new run, with all of its fuel and time.
+    pub(crate) fn start(&self) {
+        self.steps.set(0);
+        self.depth.set(0);
+        self.started.set(Some(Instant::now()));
+    }
+
+    /// Takes a step at `span`, which fails if the run is out of fuel or time.
+    #[inline]
+    pub(crate) fn step(&self, span: Span) -> Result<(), InterpreterError> {
+        self.take(1, span)
+    }
+
+    /// Takes `steps` steps at once at `span`, for natives whose work grows
+    /// with their arguments.
+    #[inline]
+    pub(crate) fn take(&self, steps: u64, span: Span) -> Result<(), InterpreterError> {
+        let before = self.steps.get();
+        let after = before.saturating_add(steps);
+        self.steps.set(after);
+        if let Some(fuel) = self.fuel {
+            if after > fuel {
+                return Err(InterpreterError::OutOfFuel(fuel, span));
+            }
+        }
+        if before / CLOCK_INTERVAL != after / CLOCK_INTERVAL {
+            if let (Some(timeout), Some(started)) = (self.timeout, self.started.get()) {
+                if started.elapsed() > timeout {
+                    return Err(InterpreterError::Timeout(timeout, span));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Enters a call made at `span`, which fails if too many are in progress.
+    /// Each call entered must be left with `leave_call`.
+    pub(crate) fn enter_call(&self, span: Span) -> Result<(), InterpreterError> {
+        let depth = self.depth.get() + 1;
+        if depth > self.max_depth {
+            return Err(InterpreterError::CallDepthExceeded(self.max_depth, span));
+        }
+        self.depth.set(depth);
+        Ok(())
+    }
+
+    pub(crate) fn leave_call(&self) {
+        let depth = self.depth.get().checked_sub(1);
+        debug_assert!(depth.is_some(), "left a call that wasn't entered");
+        self.depth.set(depth.unwrap_or(0));
+    }
+}
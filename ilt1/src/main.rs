@@ -1,35 +1,228 @@
-use lexer::Lexer;
+use ilt1::debugger::Debugger;
+use ilt1::default_fns::default_native_functions;
+use ilt1::interpreter::{interpret, Context, Exit, Output};
+use ilt1::lexer::Lexer;
+use ilt1::limits::STACK_SIZE;
+use ilt1::module::SourceMap;
+use ilt1::parser::Parser;
+use ilt1::{diagnostic, formatter, repl, test_runner};
 
-use crate::default_fns::default_native_functions;
-use crate::{interpreter::interpret, parser::Parser};
-
-mod default_fns;
-mod ast;
-mod interpreter;
-mod lexer;
-mod parser;
-mod token;
+use anyhow::Result;
+use std::cell::RefCell;
 use std::env;
 use std::fs;
 use std::io::{self, Read};
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let input = if args.len() > 1 {
+    // The tree-walker recurses for each call, and in a debug build the main
+    // thread's stack runs out long before the call depth limit is reached.
+    let cli = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(cli)
+        .expect("Failed to start the interpreter's thread");
+    if cli.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn cli() {
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("fmt") => {
+            args.next();
+            process::exit(fmt(args));
+        }
+        Some("test") => {
+            args.next();
+            process::exit(test_runner::run(args));
+        }
+        _ => {}
+    }
+
+    // Options come first, then the path. Anything after the path is passed to
+    // the program.
+    let mut context = Context {
+        warnings: Some(Output::stderr()),
+        ..Default::default()
+    };
+    let mut use_repl = false;
+    let mut debug = false;
+    let mut breakpoints = Vec::new();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repl" => use_repl = true,
+            "--allow-io" => context.allow_io = true,
+            "--vm" => context.vm = true,
+            "--warn-shadowing" => context.warn_shadowing = true,
+            "--debug" => debug = true,
+            "--break" => {
+                breakpoints.push(number(args.next(), "--break", "a line number"));
+                debug = true;
+            }
+            "--fuel" => {
+                let fuel = number(args.next(), "--fuel", "a number of steps");
+                context.limits = context.limits.with_fuel(fuel);
+            }
+            "--max-depth" => {
+                let max_depth = number(args.next(), "--max-depth", "a number of calls");
+                context.limits = context.limits.with_max_depth(max_depth);
+            }
+            "--timeout" => {
+                let seconds = number(args.next(), "--timeout", "a number of seconds");
+                let Ok(timeout) = Duration::try_from_secs_f64(seconds) else {
+                    eprintln!("error: --timeout needs a number of seconds");
+                    process::exit(1);
+                };
+                context.limits = context.limits.with_timeout(timeout);
+            }
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+    context.args = args.collect();
+
+    if use_repl {
+        repl::run(default_native_functions(), context).expect("Failed to run REPL");
+        return;
+    }
+
+    let (path, input) = if let Some(path) = path {
         // Read from file if argument is provided
-        fs::read_to_string(&args[1]).expect("Failed to read file")
+        let input = fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("error: Failed to read {}: {}", path, e);
+            process::exit(1);
+        });
+        (path, input)
     } else {
         // Otherwise, read from stdin
         let mut buffer = String::new();
         io::stdin().read_to_string(&mut buffer).expect("Failed to read from stdin");
-        buffer
+        ("<stdin>".to_owned(), buffer)
     };
 
-    let lexer = Lexer::new(&input);
-    let mut parser = Parser::try_new(lexer).expect("Failed to create parser");
-    let ast = parser.parse().expect("Failed to parse AST");
+    let sources = Rc::new(SourceMap::default());
+    let file = sources.add(path, input);
+
+    if debug {
+        if context.vm {
+            eprintln!("error: --debug only works without --vm");
+            process::exit(1);
+        }
+        let debugger = Debugger::new(
+            sources.clone(),
+            file,
+            breakpoints,
+            |line| io::stdin().read_line(line),
+            Output::default(),
+        );
+        context.debugger = Some(Rc::new(RefCell::new(debugger)));
+    }
+
+    if let Err(e) = run(&sources, file, context) {
+        if let Some(code) = Exit::code(&e) {
+            process::exit(code);
+        }
+        eprint!("{}", diagnostic::render(&e, &sources));
+        process::exit(1);
+    }
+}
 
-    let result = interpret(ast, default_native_functions()).expect("Failed to interpret AST");
+fn run(sources: &Rc<SourceMap>, file: usize, context: Context) -> Result<()> {
+    let input = sources.get(file).unwrap();
+    let lexer = Lexer::new(&input.source).with_file(file);
+    let mut parser = Parser::try_new(lexer)?;
+    let ast = parser.parse()?;
+
+    let result = interpret(ast, default_native_functions(), sources.clone(), context)?;
 
     println!("result: {:#?}", result);
+    Ok(())
+}
+
+/// Parses the value given to the option `flag`, or exits saying it needs
+/// `what`.
+fn number<T: FromStr>(arg: Option<String>, flag: &str, what: &str) -> T {
+    match arg.and_then(|arg| arg.parse().ok()) {
+        Some(number) => number,
+        None => {
+            eprintln!("error: {} needs {}", flag, what);
+            process::exit(1);
+        }
+    }
+}
+
+/// `ilt1 fmt [--check] [paths]` formats the files at `paths` in place, or
+/// stdin to stdout if there are none. With `--check`, nothing is written and
+/// the files that aren't formatted are listed. Returns the exit code, which
+/// is 1 if a file couldn't be parsed or, when checking, isn't formatted.
+fn fmt(args: impl Iterator<Item = String>) -> i32 {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    let sources = SourceMap::default();
+    let mut code = 0;
+    let stdin = paths.is_empty();
+    if stdin {
+        paths.push("<stdin>".to_owned());
+    }
+    for path in paths {
+        let input = if stdin {
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer).expect("Failed to read from stdin");
+            buffer
+        } else {
+            match fs::read_to_string(&path) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("error: Failed to read {}: {}", path, e);
+                    code = 1;
+                    continue;
+                }
+            }
+        };
+        let file = sources.add(&path, input);
+        let input = sources.get(file).unwrap();
+        let formatted = match format_file(&input.source, file) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprint!("{}", diagnostic::render(&e, &sources));
+                code = 1;
+                continue;
+            }
+        };
+
+        if check {
+            if formatted != input.source {
+                println!("{}", path);
+                code = 1;
+            }
+        } else if stdin {
+            print!("{}", formatted);
+        } else if formatted != input.source {
+            if let Err(e) = fs::write(&path, formatted) {
+                eprintln!("error: Failed to write {}: {}", path, e);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn format_file(source: &str, file: usize) -> Result<String> {
+    let mut parser = Parser::try_new(Lexer::new(source).with_file(file))?;
+    Ok(formatter::format(&parser.parse()?, source))
 }
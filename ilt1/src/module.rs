@@ -0,0 +1,246 @@
+use anyhow::Result;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use thiserror::Error;
+
+use crate::ast::AstNodeType;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::interpreter::{Context, Interpreter, InterpreterValue, NativeFn};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+
+/// A file that has been read, kept so errors can show where they happened.
+#[derive(Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub source: String,
+}
+
+/// Every file read while running a program. Spans refer to files by their
+/// index in here.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: RefCell<Vec<Rc<SourceFile>>>,
+}
+
+impl SourceMap {
+    /// Adds a file and returns its index.
+    pub fn add(&self, path: impl Into<PathBuf>, source: String) -> usize {
+        let mut files = self.files.borrow_mut();
+        files.push(Rc::new(SourceFile {
+            path: path.into(),
+            source,
+        }));
+        files.len() - 1
+    }
+
+    pub fn get(&self, file: usize) -> Option<Rc<SourceFile>> {
+        self.files.borrow().get(file).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum ModuleError {
+    #[error("Failed to read module {0}: {1}")]
+    ReadFailed(String, String, Span),
+    #[error("Import cycle: {0}")]
+    ImportCycle(String, Span),
+}
+
+impl Diagnostic for ModuleError {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::ReadFailed(_, _, span) | Self::ImportCycle(_, span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::ReadFailed(..) => Some("imports are resolved relative to the importing file"),
+            Self::ImportCycle(..) => Some("modules cannot import each other in a loop"),
+        }
+    }
+}
+
+/// The functions and constants a module defines, by name.
+pub type Namespace = Vec<(String, Rc<InterpreterValue>)>;
+
+/// Loads imported files. Each module is only run once; importing it again
+/// gives the same namespace.
+#[derive(Debug)]
+pub struct ModuleLoader {
+    pub sources: Rc<SourceMap>,
+    pub(crate) functions: RefCell<HashMap<String, NativeFn>>,
+    pub(crate) context: Rc<Context>,
+    cache: RefCell<HashMap<PathBuf, Rc<Namespace>>>,
+    /// The modules currently being loaded, to detect cycles.
+    loading: RefCell<Vec<PathBuf>>,
+}
+
+impl ModuleLoader {
+    pub fn new(
+        sources: Rc<SourceMap>,
+        functions: HashMap<String, NativeFn>,
+        context: Rc<Context>,
+    ) -> Self {
+        Self {
+            sources,
+            functions: RefCell::new(functions),
+            context,
+            cache: RefCell::new(HashMap::new()),
+            loading: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Loads the module at `path`, relative to the file containing the
+    /// import at `span`.
+    pub fn load(self: &Rc<Self>, path: &str, span: Span) -> Result<Rc<Namespace>> {
+        let base = self
+            .sources
+            .get(span.file)
+            .and_then(|file| file.path.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+        let resolved = base
+            .join(path)
+            .canonicalize()
+            .map_err(|e| ModuleError::ReadFailed(path.to_owned(), e.to_string(), span))?;
+
+        if let Some(namespace) = self.cache.borrow().get(&resolved) {
+            return Ok(namespace.clone());
+        }
+
+        if let Some(start) = self.loading.borrow().iter().position(|p| p == &resolved) {
+            let cycle = self.loading.borrow()[start..]
+                .iter()
+                .chain([&resolved])
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(ModuleError::ImportCycle(cycle, span).into());
+        }
+
+        let source = fs::read_to_string(&resolved)
+            .map_err(|e| ModuleError::ReadFailed(path.to_owned(), e.to_string(), span))?;
+
+        self.loading.borrow_mut().push(resolved.clone());
+        let namespace = self.run(&resolved, source);
+        self.loading.borrow_mut().pop();
+
+        let namespace = Rc::new(namespace?);
+        self.cache.borrow_mut().insert(resolved, namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Defines the module's imports, functions and constants in a fresh top
+    /// scope and collects the functions and constants. A `main` in a module
+    /// is not run.
+    fn run(self: &Rc<Self>, path: &Path, source: String) -> Result<Namespace> {
+        let file = self.sources.add(path, source);
+        let source = self.sources.get(file).unwrap();
+
+        let lexer = Lexer::new(&source.source).with_file(file);
+        let ast = Parser::try_new(lexer)?.parse()?;
+
+        let mut interpreter = Interpreter::with_loader(ast, self.clone());
+        interpreter.resolve()?;
+        interpreter.find_constants()?;
+
+        let mut namespace = Namespace::new();
+        for node in interpreter.ast.iter() {
+            let name = match &node.ty {
+                AstNodeType::Fn {
+                    name: Some(name), ..
+                } => name,
+                AstNodeType::Const { name, .. } => name,
+                _ => continue,
+            };
+            namespace.push((name.clone(), interpreter.top_scope.get(name, node.span)?));
+        }
+        Ok(namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_fns::default_native_functions;
+    use crate::interpreter::interpret;
+
+    /// Writes `files` into a fresh directory and runs the first one.
+    fn run(name: &str, files: &[(&str, &str)]) -> Result<Rc<InterpreterValue>> {
+        let dir = std::env::temp_dir().join(format!("ilt1-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (path, source) in files {
+            fs::write(dir.join(path), source).unwrap();
+        }
+
+        let (path, source) = files[0];
+        let sources = Rc::new(SourceMap::default());
+        let file = sources.add(dir.join(path), source.to_owned());
+        let ast = Parser::try_new(Lexer::new(source).with_file(file))?.parse()?;
+        let result = interpret(ast, default_native_functions(), sources, Context::default());
+
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_import_namespace() {
+        let result = run(
+            "namespace",
+            &[
+                (
+                    "main.thing",
+                    r#"
+                    import "math.thing"
+                    import "math.thing" as m
+                    main { (math.add m.ONE 2) }
+                    "#,
+                ),
+                ("math.thing", "const ONE 1\nfn add(a, b) { (+ a b) }\n"),
+            ],
+        )
+        .unwrap();
+        assert!(matches!(result.as_ref(), InterpreterValue::Int(3)));
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let error = run(
+            "cycle",
+            &[
+                ("a.thing", "import \"b.thing\"\nmain { }\n"),
+                ("b.thing", "import \"c.thing\"\n"),
+                ("c.thing", "import \"b.thing\"\n"),
+            ],
+        )
+        .unwrap_err();
+        let Some(ModuleError::ImportCycle(cycle, span)) = error.downcast_ref() else {
+            panic!("expected an import cycle, got {}", error);
+        };
+        let names = cycle
+            .split(" -> ")
+            .map(|p| Path::new(p).file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["b.thing", "c.thing", "b.thing"]);
+        assert_eq!(span.file, 2);
+    }
+
+    #[test]
+    fn test_import_missing_file() {
+        let error = run(
+            "missing",
+            &[("main.thing", "import \"nope.thing\"\nmain { }\n")],
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ModuleError::ReadFailed(path, _, _)) if path == "nope.thing"
+        ));
+    }
+}
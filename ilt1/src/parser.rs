@@ -1,74 +1,182 @@
 use anyhow::Result;
-use std::{iter::Peekable, vec::IntoIter};
+use std::{iter::Peekable, path::Path, vec::IntoIter};
 use thiserror::Error;
 
-use crate::ast::{AstNode, AstNodeType};
+use crate::ast::{AstNode, AstNodeType, Slot};
+use crate::diagnostic::{Diagnostic, Span};
 use crate::lexer::Lexer;
 use crate::token::{Keyword, Token, TokenType};
 
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("Unexpected token: {0:?} at {1}:{2}")]
-    UnexpectedToken(TokenType, usize, usize),
+    #[error("Unexpected token: {0:?}")]
+    UnexpectedToken(TokenType, Span),
     #[error("Unexpected end of file")]
-    UnexpectedEof,
+    UnexpectedEof(Span),
+    #[error("Unclosed delimiter")]
+    UnclosedDelimiter(Span),
+    #[error("`{0}` outside of a {1}")]
+    MisplacedKeyword(&'static str, &'static str, Span),
+    #[error("Dict key without a value")]
+    MissingDictValue(Span),
 }
 
 impl ParseError {
     pub fn new_unexpected(token: &Token) -> Self {
         match token.ty {
-            TokenType::Eof => Self::UnexpectedEof,
-            _ => Self::UnexpectedToken(token.ty.to_owned(), token.line, token.col),
+            TokenType::Eof => Self::UnexpectedEof(token.span),
+            _ => Self::UnexpectedToken(token.ty.to_owned(), token.span),
         }
     }
 
-    pub fn new_opt_ref(token: Option<&Token>) -> Self {
+    pub fn new_opt_ref(token: Option<&Token>, eof: Span) -> Self {
         match token {
             Some(token) => Self::new_unexpected(token),
-            None => Self::UnexpectedEof,
+            None => Self::UnexpectedEof(eof),
         }
     }
 
-    pub fn new_opt(token: Option<Token>) -> Self {
+    pub fn new_opt(token: Option<Token>, eof: Span) -> Self {
         match token {
             Some(token) => Self::new_unexpected(&token),
-            None => Self::UnexpectedEof,
+            None => Self::UnexpectedEof(eof),
         }
     }
 }
 
+impl Diagnostic for ParseError {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::UnexpectedToken(_, span)
+            | Self::UnexpectedEof(span)
+            | Self::UnclosedDelimiter(span)
+            | Self::MisplacedKeyword(_, _, span)
+            | Self::MissingDictValue(span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::UnexpectedToken(..) => None,
+            Self::UnexpectedEof(_) => Some("a bracket or brace may not be closed"),
+            Self::UnclosedDelimiter(_) => Some("this is never closed"),
+            Self::MisplacedKeyword("return", ..) => {
+                Some("`return` can only be used inside a function or `main`")
+            }
+            Self::MisplacedKeyword(..) => {
+                Some("loops do not extend into functions defined inside them")
+            }
+            Self::MissingDictValue(_) => Some("dicts are written as `#{key value, key value}`"),
+        }
+    }
+}
+
+/// What a `return`, `break` or `continue` would jump out of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Enclosing {
+    Function,
+    Loop,
+}
+
 /// Parses the output of the lexer into an AST.
 pub struct Parser {
     tokens: Peekable<IntoIter<Token>>,
+    enclosing: Vec<Enclosing>,
+    /// The span of the last token consumed, used to find where nodes end.
+    prev: Span,
+    /// The span of the end of the input.
+    eof: Span,
 }
 
 impl Parser {
     pub fn try_new(lexer: Lexer) -> Result<Self> {
+        let tokens = lexer.parse()?;
+        let eof = tokens.last().map(|t| t.span).unwrap_or_default();
         Ok(Self {
-            tokens: lexer.parse()?.into_iter().peekable(),
+            tokens: tokens.into_iter().peekable(),
+            enclosing: Vec::new(),
+            prev: Span::default(),
+            eof,
         })
     }
 
-    fn expect(&mut self, expected: TokenType) -> Result<(usize, usize)> {
-        if let Some(token) = self.tokens.next() {
+    /// Consumes the next token.
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.next();
+        if let Some(token) = &token {
+            self.prev = token.span;
+        }
+        token
+    }
+
+    fn unexpected_eof(&self) -> ParseError {
+        ParseError::UnexpectedEof(self.eof)
+    }
+
+    /// Parses a node with `enclosing` pushed, so control flow keywords inside
+    /// it know what they jump out of.
+    fn parse_enclosed(&mut self, enclosing: Enclosing) -> Result<Option<AstNode>> {
+        self.enclosing.push(enclosing);
+        let node = self.parse_ast_node();
+        self.enclosing.pop();
+        node
+    }
+
+    fn expect(&mut self, expected: TokenType) -> Result<Span> {
+        if let Some(token) = self.advance() {
             if token.ty == expected {
-                Ok((token.line, token.col))
+                Ok(token.span)
             } else {
                 Err(ParseError::new_unexpected(&token).into())
             }
         } else {
-            Err(ParseError::UnexpectedEof.into())
+            Err(self.unexpected_eof().into())
+        }
+    }
+
+    /// Expects the delimiter closing the one at `open`. Running out of input
+    /// instead is reported at `open`, which is more useful than the end of
+    /// the file.
+    fn expect_closing(&mut self, expected: TokenType, open: Span) -> Result<Span> {
+        if let Some(Token {
+            ty: TokenType::Eof, ..
+        }) = self.tokens.peek()
+        {
+            return Err(ParseError::UnclosedDelimiter(open).into());
         }
+        self.expect(expected)
     }
 
     pub fn parse(&mut self) -> Result<Vec<AstNode>> {
         let mut nodes = Vec::new();
 
+        while let Some(ast) = self.parse_top_level_ast()? {
+            nodes.push(ast);
+        }
+
+        Ok(nodes)
+    }
+
+    /// Parses input typed into the REPL. Besides the top level forms, bare
+    /// expressions (and `set`, `if`, `while`, ...) are allowed.
+    pub fn parse_repl_input(&mut self) -> Result<Vec<AstNode>> {
+        let mut nodes = Vec::new();
+
         loop {
-            if let Some(ast) = self.parse_top_level_ast()? {
-                nodes.push(ast);
-            } else {
-                break;
+            let node = match self.tokens.peek() {
+                Some(Token {
+                    ty: TokenType::Keyword(Keyword::Main),
+                    ..
+                }) => self.parse_main()?,
+                Some(Token {
+                    ty: TokenType::Keyword(Keyword::Import),
+                    ..
+                }) => self.parse_import()?,
+                _ => self.parse_ast_node()?,
+            };
+            match node {
+                Some(node) => nodes.push(node),
+                None => break,
             }
         }
 
@@ -80,17 +188,15 @@ impl Parser {
         let Some(token) = token else {
             return Ok(None);
         };
-        let Token { ty, line, col } = token;
-        let line = *line;
-        let col = *col;
+        let Token { ty, span } = token;
+        let span = *span;
         match ty {
             TokenType::String(s) => {
                 let s = s.clone();
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::String(s),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Keyword(k) => match k {
@@ -98,6 +204,7 @@ impl Parser {
                 Keyword::Const => self.parse_declaration(Keyword::Const),
                 Keyword::Let => self.parse_declaration(Keyword::Let),
                 Keyword::Main => self.parse_main(),
+                Keyword::Import => self.parse_import(),
                 _ => Err(ParseError::new_unexpected(token).into()),
             },
             TokenType::LParen => self.parse_call(),
@@ -111,40 +218,36 @@ impl Parser {
         let Some(token) = token else {
             return Ok(None);
         };
-        let Token { ty, line, col } = token;
-        let line = *line;
-        let col = *col;
+        let Token { ty, span } = token;
+        let span = *span;
         match ty {
             TokenType::String(s) => {
                 let s = s.clone();
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::String(s),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Int(i) => {
                 let i = *i;
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::Int(i),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Float(f) => {
                 let f = *f;
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::Float(f),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Comma => {
                 // comma is ignored
-                self.tokens.next();
+                self.advance();
                 self.parse_ast_node()
             }
             TokenType::Keyword(Keyword::Const) => self.parse_declaration(Keyword::Const),
@@ -152,51 +255,52 @@ impl Parser {
             TokenType::Keyword(Keyword::Set) => self.parse_declaration(Keyword::Set),
             TokenType::Keyword(Keyword::If) => self.parse_if(),
             TokenType::Keyword(Keyword::While) => self.parse_while(),
+            TokenType::Keyword(Keyword::For) => self.parse_for(),
             TokenType::Keyword(Keyword::Fn) => self.parse_fn(false),
+            TokenType::Keyword(Keyword::Return) => self.parse_return(),
+            TokenType::Keyword(Keyword::Break) => self.parse_loop_control(Keyword::Break),
+            TokenType::Keyword(Keyword::Continue) => self.parse_loop_control(Keyword::Continue),
             TokenType::Keyword(Keyword::True) => {
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::Bool(true),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Keyword(Keyword::False) => {
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::Bool(false),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Keyword(k) => {
-                let k = k.clone();
-                self.tokens.next();
+                let k = *k;
+                self.advance();
                 Ok(Some(AstNode {
                     ty: AstNodeType::Keyword(k),
-                    line,
-                    col,
+                    span,
                 }))
             }
             TokenType::Ident(i) => {
                 let i = i.clone();
-                self.tokens.next();
+                self.advance();
                 Ok(Some(AstNode {
-                    ty: AstNodeType::Ident(i),
-                    line,
-                    col,
+                    ty: AstNodeType::Ident(i, Slot::Unresolved),
+                    span,
                 }))
             }
             TokenType::LParen => self.parse_call(),
             TokenType::LBrace => self.parse_block(),
             TokenType::LBracket => self.parse_array(),
+            TokenType::HashLBrace => self.parse_dict(),
             TokenType::Eof => Ok(None),
             _ => Err(ParseError::new_unexpected(token).into()),
         }
     }
 
     fn parse_call(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::LParen)?;
+        let start = self.expect(TokenType::LParen)?;
 
         let name = match self.tokens.peek() {
             Some(Token {
@@ -204,42 +308,49 @@ impl Parser {
                 ..
             }) => {
                 let i = i.to_owned();
-                self.tokens.next();
+                self.advance();
                 i
             }
-            t => return Err(ParseError::new_opt_ref(t).into()),
+            Some(Token {
+                ty: TokenType::Eof, ..
+            }) => return Err(ParseError::UnclosedDelimiter(start).into()),
+            t => return Err(ParseError::new_opt_ref(t, self.eof).into()),
         };
 
         let mut params = Vec::new();
 
         loop {
             match self.tokens.peek() {
+                // Running out of input is reported below, at the `(`.
                 Some(Token {
-                    ty: TokenType::RParen,
+                    ty: TokenType::RParen | TokenType::Eof,
                     ..
                 }) => break,
                 Some(Token {
                     ty: TokenType::Comma,
                     ..
                 }) => {
-                    self.tokens.next();
+                    self.advance();
                 }
-                Some(_) => params.push(self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?),
-                None => return Err(ParseError::UnexpectedEof.into()),
+                Some(_) => params.push(self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?),
+                None => return Err(self.unexpected_eof().into()),
             }
         }
 
-        self.expect(TokenType::RParen)?;
+        self.expect_closing(TokenType::RParen, start)?;
 
         Ok(Some(AstNode {
-            ty: AstNodeType::Call { name, params },
-            line,
-            col,
+            ty: AstNodeType::Call {
+                name,
+                slot: Slot::Unresolved,
+                params,
+            },
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_block(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::LBrace)?;
+        let start = self.expect(TokenType::LBrace)?;
 
         let mut nodes = Vec::new();
 
@@ -259,17 +370,16 @@ impl Parser {
             }
         }
 
-        self.expect(TokenType::RBrace)?;
+        self.expect_closing(TokenType::RBrace, start)?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::Block(nodes),
-            line,
-            col,
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_array(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::LBracket)?;
+        let start = self.expect(TokenType::LBracket)?;
 
         let mut nodes = Vec::new();
 
@@ -289,17 +399,61 @@ impl Parser {
             }
         }
 
-        self.expect(TokenType::RBracket)?;
+        self.expect_closing(TokenType::RBracket, start)?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::Array(nodes),
-            line,
-            col,
+            span: start.to(self.prev),
+        }))
+    }
+
+    fn parse_dict(&mut self) -> Result<Option<AstNode>> {
+        let start = self.expect(TokenType::HashLBrace)?;
+
+        let mut entries = Vec::new();
+
+        loop {
+            match self.tokens.peek() {
+                Some(Token {
+                    ty: TokenType::RBrace,
+                    ..
+                }) => break,
+                Some(Token {
+                    ty: TokenType::Comma,
+                    ..
+                }) => {
+                    self.advance();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(key) = self.parse_ast_node()? else {
+                break;
+            };
+            if let Some(Token {
+                ty: TokenType::RBrace | TokenType::Comma,
+                ..
+            }) = self.tokens.peek()
+            {
+                return Err(ParseError::MissingDictValue(key.span).into());
+            }
+            let Some(value) = self.parse_ast_node()? else {
+                break;
+            };
+            entries.push((key, value));
+        }
+
+        self.expect_closing(TokenType::RBrace, start)?;
+
+        Ok(Some(AstNode {
+            ty: AstNodeType::Dict(entries),
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_fn(&mut self, top_level: bool) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::Keyword(Keyword::Fn))?;
+        let start = self.expect(TokenType::Keyword(Keyword::Fn))?;
 
         let name = match self.tokens.peek() {
             Some(Token {
@@ -307,17 +461,23 @@ impl Parser {
                 ..
             }) => {
                 let s = i.to_owned();
-                self.tokens.next();
-                s
+                self.advance();
+                Some(s)
             }
-            Some(t) => {
+            // `fn(params) body` and `fn body` are lambdas, which only make
+            // sense where a value is expected.
+            Some(
+                t @ Token {
+                    ty: TokenType::LParen | TokenType::LBrace,
+                    ..
+                },
+            ) => {
                 if top_level {
                     return Err(ParseError::new_unexpected(t).into());
-                } else {
-                    "Anonymous Function".to_owned()
                 }
+                None
             }
-            None => return Err(ParseError::UnexpectedEof.into()),
+            t => return Err(ParseError::new_opt_ref(t, self.eof).into()),
         };
 
         let mut params = Vec::new();
@@ -327,102 +487,141 @@ impl Parser {
                 ty: TokenType::LParen,
                 ..
             }) => {
-                self.tokens.next();
+                let open = self.advance().unwrap().span;
                 loop {
                     if let Some(Token {
-                        ty: TokenType::RParen,
+                        ty: TokenType::RParen | TokenType::Eof,
                         ..
                     }) = self.tokens.peek()
                     {
                         break;
                     }
 
-                    match self.tokens.next() {
+                    match self.advance() {
                         Some(Token {
                             ty: TokenType::Comma,
                             ..
                         }) => {}
                         Some(Token {
                             ty: TokenType::Ident(i),
-                            ..
+                            span,
                         }) => {
                             params.push(AstNode {
-                                ty: AstNodeType::Ident(i.to_owned()),
-                                line: 0,
-                                col: 0,
+                                ty: AstNodeType::Ident(i, Slot::Unresolved),
+                                span,
                             });
                         }
-                        t => return Err(ParseError::new_opt(t).into()),
+                        t => return Err(ParseError::new_opt(t, self.eof).into()),
                     }
                 }
-                self.expect(TokenType::RParen)?;
+                self.expect_closing(TokenType::RParen, open)?;
             }
             Some(Token {
                 ty: TokenType::LBrace,
                 ..
             }) => {}
-            t => return Err(ParseError::new_opt_ref(t).into()),
+            t => return Err(ParseError::new_opt_ref(t, self.eof).into()),
         }
 
-        let body = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let body = self
+            .parse_enclosed(Enclosing::Function)?
+            .ok_or_else(|| self.unexpected_eof())?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::Fn {
                 name,
+                slot: Slot::Unresolved,
                 params,
                 body: Box::new(body),
             },
-            line,
-            col,
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_declaration(&mut self, keyword: Keyword) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::Keyword(keyword))?;
+        let start = self.expect(TokenType::Keyword(keyword))?;
 
-        let name = match self.tokens.next() {
+        let name = match self.advance() {
             Some(Token {
                 ty: TokenType::Ident(i),
                 ..
             }) => i,
-            t => return Err(ParseError::new_opt(t).into()),
+            t => return Err(ParseError::new_opt(t, self.eof).into()),
         };
 
-        let value = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let value = self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::declaration(keyword, name, value),
-            line,
-            col,
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_main(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::Keyword(Keyword::Main))?;
+        let start = self.expect(TokenType::Keyword(Keyword::Main))?;
 
-        let body = self.parse_block()?.ok_or(ParseError::UnexpectedEof)?;
+        self.enclosing.push(Enclosing::Function);
+        let body = self.parse_block();
+        self.enclosing.pop();
+        let body = body?.ok_or_else(|| self.unexpected_eof())?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::Main(Box::new(body)),
-            line,
-            col,
+            span: start.to(self.prev),
+        }))
+    }
+
+    fn parse_import(&mut self) -> Result<Option<AstNode>> {
+        let start = self.expect(TokenType::Keyword(Keyword::Import))?;
+
+        let path = match self.advance() {
+            Some(Token {
+                ty: TokenType::String(s),
+                ..
+            }) => s,
+            t => return Err(ParseError::new_opt(t, self.eof).into()),
+        };
+
+        let alias = match self.tokens.peek() {
+            Some(Token {
+                ty: TokenType::Ident(i),
+                ..
+            }) if i == "as" => {
+                self.advance();
+                match self.advance() {
+                    Some(Token {
+                        ty: TokenType::Ident(i),
+                        ..
+                    }) => i,
+                    t => return Err(ParseError::new_opt(t, self.eof).into()),
+                }
+            }
+            _ => Path::new(&path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone()),
+        };
+
+        Ok(Some(AstNode {
+            ty: AstNodeType::Import { path, alias },
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_if(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::Keyword(Keyword::If))?;
+        let start = self.expect(TokenType::Keyword(Keyword::If))?;
 
-        let condition = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let condition = self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?;
 
-        let body = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let body = self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?;
 
         let else_body = match self.tokens.peek() {
             Some(Token {
                 ty: TokenType::Keyword(Keyword::Else),
                 ..
             }) => {
-                self.tokens.next();
-                Some(self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?)
+                self.advance();
+                Some(self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?)
             }
             _ => None,
         };
@@ -433,25 +632,104 @@ impl Parser {
                 body: Box::new(body),
                 else_body: else_body.map(Box::new),
             },
-            line,
-            col,
+            span: start.to(self.prev),
         }))
     }
 
     fn parse_while(&mut self) -> Result<Option<AstNode>> {
-        let (line, col) = self.expect(TokenType::Keyword(Keyword::While))?;
+        let start = self.expect(TokenType::Keyword(Keyword::While))?;
 
-        let condition = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let condition = self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?;
 
-        let body = self.parse_ast_node()?.ok_or(ParseError::UnexpectedEof)?;
+        let body = self
+            .parse_enclosed(Enclosing::Loop)?
+            .ok_or_else(|| self.unexpected_eof())?;
 
         Ok(Some(AstNode {
             ty: AstNodeType::While {
                 condition: Box::new(condition),
                 body: Box::new(body),
             },
-            line,
-            col,
+            span: start.to(self.prev),
+        }))
+    }
+
+    /// Parses `for name in iterable { ... }`. Like `as` in imports, `in` is
+    /// only special here.
+    fn parse_for(&mut self) -> Result<Option<AstNode>> {
+        let start = self.expect(TokenType::Keyword(Keyword::For))?;
+
+        let name = match self.advance() {
+            Some(Token {
+                ty: TokenType::Ident(i),
+                ..
+            }) => i,
+            t => return Err(ParseError::new_opt(t, self.eof).into()),
+        };
+
+        match self.advance() {
+            Some(Token {
+                ty: TokenType::Ident(i),
+                ..
+            }) if i == "in" => {}
+            t => return Err(ParseError::new_opt(t, self.eof).into()),
+        }
+
+        let iterable = self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?;
+
+        let body = self
+            .parse_enclosed(Enclosing::Loop)?
+            .ok_or_else(|| self.unexpected_eof())?;
+
+        Ok(Some(AstNode {
+            ty: AstNodeType::For {
+                name,
+                iterable: Box::new(iterable),
+                body: Box::new(body),
+            },
+            span: start.to(self.prev),
         }))
     }
+
+    /// Parses `return`, optionally followed by a value. A `return` directly
+    /// before the end of a block returns Void.
+    fn parse_return(&mut self) -> Result<Option<AstNode>> {
+        let start = self.expect(TokenType::Keyword(Keyword::Return))?;
+
+        if !self.enclosing.contains(&Enclosing::Function) {
+            return Err(ParseError::MisplacedKeyword("return", "function", start).into());
+        }
+
+        let value = match self.tokens.peek() {
+            Some(Token {
+                ty: TokenType::RBrace | TokenType::Eof,
+                ..
+            })
+            | None => None,
+            Some(_) => Some(self.parse_ast_node()?.ok_or_else(|| self.unexpected_eof())?),
+        };
+
+        Ok(Some(AstNode {
+            ty: AstNodeType::Return(value.map(Box::new)),
+            span: start.to(self.prev),
+        }))
+    }
+
+    /// Parses `break` or `continue`. Loops do not extend into functions
+    /// defined inside them.
+    fn parse_loop_control(&mut self, keyword: Keyword) -> Result<Option<AstNode>> {
+        let start = self.expect(TokenType::Keyword(keyword))?;
+
+        let (name, ty) = match keyword {
+            Keyword::Break => ("break", AstNodeType::Break),
+            Keyword::Continue => ("continue", AstNodeType::Continue),
+            _ => unreachable!(),
+        };
+
+        if self.enclosing.last() != Some(&Enclosing::Loop) {
+            return Err(ParseError::MisplacedKeyword(name, "loop", start).into());
+        }
+
+        Ok(Some(AstNode { ty, span: start }))
+    }
 }
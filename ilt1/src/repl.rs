@@ -0,0 +1,152 @@
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::engine::Engine;
+use crate::interpreter::{Context, Exit, InterpreterValue, NativeFn};
+use crate::lexer::{LexError, Lexer};
+use crate::token::TokenType;
+
+/// An interactive session. Everything entered is evaluated in one top scope,
+/// so definitions stick around between inputs.
+pub struct Repl {
+    engine: Engine,
+}
+
+impl Repl {
+    pub fn new(functions: HashMap<String, NativeFn>, context: Context) -> Self {
+        Self {
+            engine: Engine::with_natives(functions, context),
+        }
+    }
+
+    /// Evaluates one complete input and returns the value of each top level
+    /// node in it.
+    pub fn eval(&mut self, input: &str) -> Result<Vec<Rc<InterpreterValue>>> {
+        self.engine.eval_each("<repl>", input)
+    }
+
+    /// Reads inputs a line at a time with `read_line` until it runs out or
+    /// `exit` is called, printing results and errors to `output`.
+    pub fn run(
+        &mut self,
+        mut read_line: impl FnMut(&mut String) -> io::Result<usize>,
+        mut output: impl Write,
+    ) -> Result<()> {
+        let mut buffer = String::new();
+
+        loop {
+            write!(output, "{}", if buffer.is_empty() { "> " } else { ". " })?;
+            output.flush()?;
+
+            if read_line(&mut buffer)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+
+            if !is_complete(&buffer) {
+                continue;
+            }
+
+            match self.eval(&buffer) {
+                Ok(results) => {
+                    for result in results {
+                        if !matches!(result.as_ref(), InterpreterValue::Void) {
+                            writeln!(output, "{}", result.to_formatted_string())?;
+                        }
+                    }
+                }
+                Err(e) if Exit::code(&e).is_some() => return Ok(()),
+                Err(e) => write!(output, "{}", self.engine.render_error(&e))?,
+            }
+            buffer.clear();
+        }
+    }
+}
+
+/// Runs a REPL on stdin and stdout. Stdin is only locked while reading a
+/// line, so the `read_line` native can use it too.
+pub fn run(functions: HashMap<String, NativeFn>, context: Context) -> Result<()> {
+    Repl::new(functions, context).run(|line| io::stdin().read_line(line), io::stdout())
+}
+
+/// Whether `input` can be evaluated, or whether more lines are needed because
+/// a string or bracket is still open. Input with other lex errors is
+/// "complete" so the error gets reported.
+fn is_complete(input: &str) -> bool {
+    let tokens = match Lexer::new(input).parse() {
+        Ok(tokens) => tokens,
+        Err(e) => return !matches!(e.downcast_ref(), Some(LexError::UnexpectedEOF(_))),
+    };
+
+    let mut depth = 0isize;
+    for token in tokens {
+        match token.ty {
+            TokenType::LParen
+            | TokenType::LBrace
+            | TokenType::HashLBrace
+            | TokenType::LBracket => depth += 1,
+            TokenType::RParen | TokenType::RBrace | TokenType::RBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_fns::default_native_functions;
+    use std::io::BufRead;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("(+ 1 2)\n"));
+        assert!(is_complete("\n"));
+        assert!(!is_complete("fn f(x) {\n"));
+        assert!(!is_complete("(+ 1\n"));
+        assert!(!is_complete("\"unterminated\n"));
+        assert!(is_complete("fn f(x) {\n  x\n}\n"));
+        assert!(is_complete(")\n"));
+    }
+
+    #[test]
+    fn test_definitions_persist() {
+        let mut repl = Repl::new(default_native_functions(), Context::default());
+        repl.eval("fn double(x) { (* x 2) }").unwrap();
+        repl.eval("let a 5").unwrap();
+        repl.eval("const b 1").unwrap();
+        let results = repl.eval("(double a) b").unwrap();
+        assert!(matches!(results[0].as_ref(), InterpreterValue::Int(10)));
+        assert!(matches!(results[1].as_ref(), InterpreterValue::Int(1)));
+    }
+
+    #[test]
+    fn test_errors_keep_the_session() {
+        let mut repl = Repl::new(default_native_functions(), Context::default());
+        repl.eval("let a 1").unwrap();
+        assert!(repl.eval("(+ a \"b\")").is_err());
+        assert!(repl.eval("}").is_err());
+        assert!(repl.eval("else").is_err());
+        let results = repl.eval("a").unwrap();
+        assert!(matches!(results[0].as_ref(), InterpreterValue::Int(1)));
+    }
+
+    #[test]
+    fn test_run_multiline() {
+        let input = "fn add(a, b) {\n  (+ a b)\n}\n(add 1 2)\n\"hi\"\n(nope)\n";
+        let mut output = Vec::new();
+        let mut input = input.as_bytes();
+        Repl::new(default_native_functions(), Context::default())
+            .run(|line| input.read_line(line), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("> . . Function { name: add"));
+        assert!(output.contains("\n> 3\n"));
+        assert!(output.contains("\n> \"hi\"\n"));
+        assert!(output.contains("> error: Function `nope` not found\n --> <repl>:1:1\n"));
+    }
+}
@@ -0,0 +1,593 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+use crate::ast::{AstNode, AstNodeType, Slot};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::interpreter::Environment;
+
+#[derive(Debug, Clone, Error)]
+pub enum ResolveError {
+    #[error("Variable `{0}` not found")]
+    VariableNotFound(String, Span),
+    #[error("Function `{0}` not found")]
+    FunctionNotFound(String, Span),
+    #[error("Wrong number of arguments for `{0}`: expected {1}, found {2}")]
+    ArgumentCount(String, usize, usize, Span),
+}
+
+impl Diagnostic for ResolveError {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::VariableNotFound(_, span)
+            | Self::FunctionNotFound(_, span)
+            | Self::ArgumentCount(_, _, _, span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::VariableNotFound(..) => {
+                Some("variables must be declared with `let` or `const` before they are used")
+            }
+            Self::FunctionNotFound(..) => Some("functions are declared with `fn`"),
+            Self::ArgumentCount(..) => None,
+        }
+    }
+}
+
+/// Something that is probably a mistake, but doesn't stop the program from
+/// running.
+#[derive(Debug, Clone, Error)]
+pub enum ResolveWarning {
+    #[error("`{0}` shadows a variable of an outer scope")]
+    Shadowing(String, Span),
+}
+
+impl Diagnostic for ResolveWarning {
+    fn span(&self) -> Option<Span> {
+        match self {
+            Self::Shadowing(_, span) => Some(*span),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Shadowing(..) => Some("rename it, or use `set` to change the outer variable"),
+        }
+    }
+}
+
+/// Every problem found while resolving a program, in the order they appear.
+#[derive(Debug, Error)]
+#[error("{}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))]
+pub struct ResolveErrors(pub Vec<ResolveError>);
+
+/// The names declared in a block or function, which has a frame of its own
+/// when it runs.
+#[derive(Debug, Default)]
+struct Scope {
+    /// Names declared so far, with the number of parameters if they are
+    /// bound to a function.
+    declared: HashMap<String, Option<usize>>,
+    /// Names the block declares further on. Code in functions defined in the
+    /// block only runs when the function is called, so it can use them.
+    later: HashMap<String, Option<usize>>,
+    /// The index of each variable in the frame.
+    slots: HashMap<String, usize>,
+    /// How many variables the frame has.
+    size: usize,
+    /// How many functions this scope is nested in.
+    depth: usize,
+}
+
+impl Scope {
+    /// The index of `name` in the frame, giving it the next free one if it
+    /// has none yet. Declaring a name again reuses its slot, so closures
+    /// see the new value as they would have done before.
+    fn index(&mut self, name: &str) -> usize {
+        if let Some(index) = self.slots.get(name) {
+            return *index;
+        }
+        self.slots.insert(name.to_owned(), self.size);
+        self.size += 1;
+        self.size - 1
+    }
+}
+
+/// Checks that every name a program uses is declared somewhere it can be
+/// seen from, and that calls to functions declared with `fn` have the right
+/// number of arguments. Names that are only known at run time (variables
+/// of imported modules, or functions reassigned with `set`) are not checked
+/// further than that.
+///
+/// Each variable is also given the slot it is stored in, so looking it up
+/// at run time doesn't need its name. The outermost scope is the top frame
+/// of the program, `globals`.
+///
+/// Declarations that shadow a variable the program declared in an outer
+/// scope are collected as warnings.
+struct Resolver<'a> {
+    scopes: Vec<Scope>,
+    globals: &'a Environment,
+    /// The aliases of imported modules.
+    modules: HashSet<String>,
+    errors: Vec<ResolveError>,
+    warnings: Vec<ResolveWarning>,
+}
+
+/// Resolves a whole program. The top level declarations are visible
+/// everywhere, as they are all defined before `main` runs. `globals` is the
+/// top frame the program runs in, which already holds the natives.
+pub fn resolve_program(
+    ast: &mut [AstNode],
+    globals: &Environment,
+) -> Result<Vec<ResolveWarning>> {
+    let mut resolver = Resolver::new(globals);
+    for node in ast.iter_mut() {
+        match &mut node.ty {
+            AstNodeType::Const { name, slot, value } => {
+                *slot = resolver.declare(name, arity(value));
+            }
+            AstNodeType::Fn {
+                name: Some(name),
+                slot,
+                params,
+                ..
+            } => *slot = resolver.declare(name, Some(params.len())),
+            AstNodeType::Import { alias, .. } => {
+                resolver.modules.insert(alias.clone());
+            }
+            _ => {}
+        }
+    }
+    for node in ast.iter_mut() {
+        match &mut node.ty {
+            AstNodeType::Const { value, .. } => resolver.resolve(value),
+            AstNodeType::Fn { params, body, .. } => resolver.resolve_function(params, body),
+            _ => resolver.resolve(node),
+        }
+    }
+    resolver.finish()
+}
+
+/// Resolves nodes that are run one after the other in the top frame,
+/// `globals`, as the REPL does.
+pub fn resolve_statements(
+    nodes: &mut [AstNode],
+    globals: &Environment,
+) -> Result<Vec<ResolveWarning>> {
+    let mut resolver = Resolver::new(globals);
+    for node in nodes.iter_mut() {
+        resolver.resolve(node);
+    }
+    resolver.finish()
+}
+
+/// The number of parameters of the function `value` evaluates to, if it is
+/// a function literal.
+fn arity(value: &AstNode) -> Option<usize> {
+    match &value.ty {
+        AstNodeType::Fn { params, .. } => Some(params.len()),
+        _ => None,
+    }
+}
+
+impl<'a> Resolver<'a> {
+    fn new(globals: &'a Environment) -> Self {
+        Self {
+            scopes: vec![Scope::default()],
+            globals,
+            modules: HashSet::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<ResolveWarning>> {
+        if self.errors.is_empty() {
+            Ok(self.warnings)
+        } else {
+            Err(ResolveErrors(self.errors).into())
+        }
+    }
+
+    /// Declares a name in the innermost scope and returns its slot.
+    fn declare(&mut self, name: &str, arity: Option<usize>) -> Slot {
+        let top = self.scopes.len() == 1;
+        let scope = self.scopes.last_mut().unwrap();
+        scope.declared.insert(name.to_owned(), arity);
+        if top {
+            Slot::Global(self.globals.index_of(name))
+        } else {
+            Slot::Local {
+                depth: 0,
+                index: scope.index(name),
+            }
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.scopes.last().unwrap().depth
+    }
+
+    /// Finds a name, returning its slot and the number of parameters it
+    /// takes if it is known to be a function.
+    fn lookup(&self, name: &str) -> Option<(Slot, Option<usize>)> {
+        let depth = self.depth();
+        let innermost = self.scopes.len() - 1;
+        for (i, scope) in self.scopes.iter().enumerate().rev() {
+            let arity = match scope.declared.get(name) {
+                Some(arity) => arity,
+                None if scope.depth < depth => match scope.later.get(name) {
+                    Some(arity) => arity,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let slot = match i {
+                0 => Slot::Global(self.globals.index_of(name)),
+                _ => Slot::Local {
+                    depth: innermost - i,
+                    index: scope.slots[name],
+                },
+            };
+            return Some((slot, *arity));
+        }
+        let defined = self.globals.get(name).is_some()
+            || matches!(name.split_once('.'), Some((alias, _)) if self.modules.contains(alias));
+        defined.then(|| (Slot::Global(self.globals.index_of(name)), None))
+    }
+
+    /// Whether `name` is declared in a scope enclosing the innermost one and
+    /// visible from it.
+    fn shadows(&self, name: &str) -> bool {
+        let depth = self.depth();
+        let (_, outer) = self.scopes.split_last().unwrap();
+        outer.iter().any(|scope| {
+            scope.declared.contains_key(name)
+                || (scope.depth < depth && scope.later.contains_key(name))
+        })
+    }
+
+    /// Runs `f` in a new innermost scope.
+    fn with_scope(&mut self, scope: Scope, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(scope);
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn resolve_function(&mut self, params: &mut [AstNode], body: &mut AstNode) {
+        let mut scope = Scope {
+            size: params.len(),
+            depth: self.depth() + 1,
+            ..Default::default()
+        };
+        // Calls fill in the arguments in order.
+        for (index, param) in params.iter_mut().enumerate() {
+            if let AstNodeType::Ident(name, slot) = &mut param.ty {
+                scope.declared.insert(name.clone(), None);
+                scope.slots.insert(name.clone(), index);
+                *slot = Slot::Local { depth: 0, index };
+            }
+        }
+        self.with_scope(scope, |resolver| resolver.resolve(body));
+    }
+
+    fn resolve_block(&mut self, nodes: &mut [AstNode]) {
+        let mut scope = Scope {
+            depth: self.depth(),
+            ..Default::default()
+        };
+        for node in nodes.iter() {
+            let (name, arity) = match &node.ty {
+                AstNodeType::Const { name, value, .. } | AstNodeType::Let { name, value, .. } => {
+                    (name, arity(value))
+                }
+                AstNodeType::Fn {
+                    name: Some(name),
+                    params,
+                    ..
+                } => (name, Some(params.len())),
+                _ => continue,
+            };
+            scope.later.insert(name.clone(), arity);
+            scope.index(name);
+        }
+        self.with_scope(scope, |resolver| {
+            for node in nodes.iter_mut() {
+                resolver.resolve(node);
+            }
+        });
+    }
+
+    /// Resolves a use of a variable, recording its slot.
+    fn resolve_name(&mut self, name: &str, slot: &mut Slot, span: Span) {
+        match self.lookup(name) {
+            Some((found, _)) => *slot = found,
+            None => self
+                .errors
+                .push(ResolveError::VariableNotFound(name.to_owned(), span)),
+        }
+    }
+
+    fn resolve(&mut self, node: &mut AstNode) {
+        let span = node.span;
+        match &mut node.ty {
+            AstNodeType::Int(_)
+            | AstNodeType::Float(_)
+            | AstNodeType::String(_)
+            | AstNodeType::Bool(_)
+            | AstNodeType::Keyword(_)
+            | AstNodeType::Break
+            | AstNodeType::Continue
+            | AstNodeType::Return(None) => {}
+            AstNodeType::Ident(name, slot) => self.resolve_name(name, slot, span),
+            AstNodeType::Fn {
+                name,
+                slot,
+                params,
+                body,
+            } => {
+                // The function is bound before its body can run, so it can
+                // call itself.
+                if let Some(name) = name {
+                    *slot = self.declare(name, Some(params.len()));
+                }
+                self.resolve_function(params, body);
+            }
+            AstNodeType::Const { name, slot, value } | AstNodeType::Let { name, slot, value } => {
+                self.resolve(value);
+                if self.shadows(name) {
+                    self.warnings
+                        .push(ResolveWarning::Shadowing(name.clone(), span));
+                }
+                *slot = self.declare(name, arity(value));
+            }
+            AstNodeType::Set { name, slot, value } => {
+                self.resolve(value);
+                self.resolve_name(name, slot, span);
+                // It may not be the same function any more.
+                if let Some(scope) = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find(|scope| scope.declared.contains_key(name.as_str()))
+                {
+                    scope.declared.insert(name.clone(), None);
+                }
+            }
+            AstNodeType::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                self.resolve(condition);
+                self.resolve(body);
+                if let Some(else_body) = else_body {
+                    self.resolve(else_body);
+                }
+            }
+            AstNodeType::While { condition, body } => {
+                self.resolve(condition);
+                self.resolve(body);
+            }
+            AstNodeType::For {
+                name,
+                iterable,
+                body,
+            } => {
+                self.resolve(iterable);
+                // The loop variable is alone in the frame of each iteration.
+                let mut scope = Scope {
+                    size: 1,
+                    depth: self.depth(),
+                    ..Default::default()
+                };
+                scope.declared.insert(name.clone(), None);
+                scope.slots.insert(name.clone(), 0);
+                self.with_scope(scope, |resolver| {
+                    if resolver.shadows(name) {
+                        resolver
+                            .warnings
+                            .push(ResolveWarning::Shadowing(name.clone(), span));
+                    }
+                    resolver.resolve(body);
+                });
+            }
+            AstNodeType::Return(Some(value)) | AstNodeType::Main(value) => self.resolve(value),
+            AstNodeType::Import { alias, .. } => {
+                self.modules.insert(alias.clone());
+            }
+            AstNodeType::Call { name, slot, params } => {
+                match self.lookup(name) {
+                    None => self
+                        .errors
+                        .push(ResolveError::FunctionNotFound(name.clone(), span)),
+                    Some((found, Some(arity))) if arity != params.len() => {
+                        *slot = found;
+                        self.errors.push(ResolveError::ArgumentCount(
+                            name.clone(),
+                            arity,
+                            params.len(),
+                            span,
+                        ))
+                    }
+                    Some((found, _)) => *slot = found,
+                }
+                for param in params {
+                    self.resolve(param);
+                }
+            }
+            AstNodeType::Block(nodes) => self.resolve_block(nodes),
+            AstNodeType::Array(nodes) => {
+                for node in nodes {
+                    self.resolve(node);
+                }
+            }
+            AstNodeType::Dict(entries) => {
+                for (key, value) in entries {
+                    self.resolve(key);
+                    self.resolve(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::InterpreterValue, lexer::Lexer, parser::Parser};
+    use std::rc::Rc;
+
+    fn errors(input: &str) -> Vec<String> {
+        let mut ast = Parser::try_new(Lexer::new(input)).unwrap().parse().unwrap();
+        let globals = Environment::default();
+        globals.set("print", Rc::new(InterpreterValue::Void));
+        globals.set("+", Rc::new(InterpreterValue::Void));
+        match resolve_program(&mut ast, &globals) {
+            Ok(_) => Vec::new(),
+            Err(e) => e
+                .downcast::<ResolveErrors>()
+                .unwrap()
+                .0
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolves_declarations() {
+        let input = r#"
+            import "math.thing"
+
+            fn fact(n) { (fact (+ n -1)) }
+
+            main {
+                (print (fact LIMIT) (math.square 2))
+                let x 1
+                fn later() { (print x y (helper)) }
+                let y 2
+                fn helper() { y }
+                (later)
+            }
+
+            const LIMIT 5
+        "#;
+        assert_eq!(errors(input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let input = r#"
+            fn add(a, b) { (+ a b) }
+
+            main {
+                (print x)
+                let x 1
+                (add 1)
+                (nope x)
+                if true { let inner 1 }
+                set inner 2
+                (print (other.thing))
+            }
+        "#;
+        assert_eq!(
+            errors(input),
+            vec![
+                "Variable `x` not found",
+                "Wrong number of arguments for `add`: expected 2, found 1",
+                "Function `nope` not found",
+                "Variable `inner` not found",
+                "Function `other.thing` not found",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_forgets_arity() {
+        let input = r#"
+            fn one(a) { a }
+            fn two(a, b) { a }
+
+            main {
+                let f one
+                (f 1 2)
+                (one 1 2)
+                set one two
+                (one 1 2)
+            }
+        "#;
+        assert_eq!(
+            errors(input),
+            vec!["Wrong number of arguments for `one`: expected 1, found 2"]
+        );
+    }
+
+    #[test]
+    fn test_warns_about_shadowing() {
+        let input = r#"
+            const LIMIT 5
+
+            fn f(x) {
+                let x (+ x 1)
+                let y x
+                let y 2
+                if true {
+                    const LIMIT y
+                    let print 3
+                }
+                fn() { let z 1 }
+                let z 2
+                for z in [] {
+                    let z 3
+                }
+            }
+        "#;
+        let mut ast = Parser::try_new(Lexer::new(input)).unwrap().parse().unwrap();
+        let globals = Environment::default();
+        globals.set("print", Rc::new(InterpreterValue::Void));
+        globals.set("+", Rc::new(InterpreterValue::Void));
+        let warnings = resolve_program(&mut ast, &globals).unwrap();
+        assert_eq!(
+            warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+            vec![
+                "`x` shadows a variable of an outer scope",
+                "`LIMIT` shadows a variable of an outer scope",
+                "`z` shadows a variable of an outer scope",
+                "`z` shadows a variable of an outer scope",
+                "`z` shadows a variable of an outer scope",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assigns_slots() {
+        let input = "main { let a 1 fn f(x) { (+ a x) } }";
+        let mut ast = Parser::try_new(Lexer::new(input)).unwrap().parse().unwrap();
+        let globals = Environment::default();
+        globals.set("+", Rc::new(InterpreterValue::Void));
+        resolve_program(&mut ast, &globals).unwrap();
+
+        let AstNodeType::Main(main) = &ast[0].ty else { unreachable!() };
+        let AstNodeType::Block(nodes) = &main.ty else { unreachable!() };
+        let AstNodeType::Fn { slot, body, .. } = &nodes[1].ty else { unreachable!() };
+        assert_eq!(*slot, Slot::Local { depth: 0, index: 1 });
+        let AstNodeType::Block(body) = &body.ty else { unreachable!() };
+        let AstNodeType::Call { slot, params, .. } = &body[0].ty else { unreachable!() };
+        assert_eq!(*slot, Slot::Global(globals.index_of("+")));
+        let slots = params
+            .iter()
+            .map(|param| match &param.ty {
+                AstNodeType::Ident(_, slot) => *slot,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slots,
+            [Slot::Local { depth: 2, index: 0 }, Slot::Local { depth: 1, index: 0 }]
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::Result;
+use std::{cell::RefCell, collections::HashMap, fs, path::Path, rc::Rc};
+
+use crate::default_fns::default_native_functions;
+use crate::diagnostic;
+use crate::interpreter::{interpret, Context, Exit, NativeFn, Output};
+use crate::lexer::Lexer;
+use crate::module::SourceMap;
+use crate::parser::Parser;
+use crate::token::{Token, TokenType};
+
+/// Strings starting with this give a line that the `print` calls on the same
+/// line should print.
+const ANNOTATION: &str = "Expected: ";
+
+/// A string starting with this, after any blank lines, gives everything the
+/// program should print, one line per line after it.
+const BLOCK: &str = "Expected output:";
+
+/// Starts the line with the program's result that running it prints last.
+/// An expected output block can end with it.
+const RESULT: &str = "result: ";
+
+/// The lines a program should print, and where each of them is given.
+struct Expected {
+    /// The file the lines are given in: the program, or its `.out` file.
+    path: String,
+    /// Each line along with its line number in `path`.
+    lines: Vec<(usize, String)>,
+    /// Whether the lines are annotations, each giving a line printed by the
+    /// calls on the line it is on, rather than all of the program's output.
+    annotations: bool,
+}
+
+/// The lines printed by `print` and `dbg`, each with the line of the call
+/// that printed it.
+#[derive(Default)]
+struct PrintedAt {
+    lines: Vec<(usize, String)>,
+    /// How much of the output has been given to a call, so that a call made
+    /// while evaluating another's arguments isn't counted twice.
+    end: usize,
+}
+
+/// How a program's run compared with what it should print.
+enum Outcome {
+    Passed,
+    Failed(Vec<String>),
+    /// The program doesn't say what it should print.
+    Skipped,
+}
+
+/// `ilt1 test [--vm] [--allow-io] paths` runs each program at `paths` with
+/// its output captured, and compares it with the program's `.out` file if
+/// it has one, or else with its `"Expected output: ..."` block, or else with
+/// its `"Expected: ..."` annotations. The differences are listed with a
+/// summary. Returns the exit code, which is 1
+/// if any program failed.
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let (mut vm, mut allow_io) = (false, false);
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--vm" => vm = true,
+            "--allow-io" => allow_io = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in paths {
+        let context = Context {
+            vm,
+            allow_io,
+            ..Default::default()
+        };
+        match test_file(&path, context) {
+            Outcome::Passed => {
+                println!("PASS {}", path);
+                passed += 1;
+            }
+            Outcome::Failed(differences) => {
+                println!("FAIL {}", path);
+                for difference in differences {
+                    println!("    {}", difference.trim_end().replace('\n', "\n    "));
+                }
+                failed += 1;
+            }
+            Outcome::Skipped => {
+                println!("SKIP {} (nothing is expected)", path);
+                skipped += 1;
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+fn test_file(path: &str, mut context: Context) -> Outcome {
+    let input = match fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) => return Outcome::Failed(vec![format!("Failed to read {}: {}", path, e)]),
+    };
+    let sources = Rc::new(SourceMap::default());
+    let file = sources.add(path, input);
+    let source = &sources.get(file).unwrap().source;
+
+    let expected = match expected_output(path, source, file) {
+        Ok(Some(expected)) => expected,
+        Ok(None) => return Outcome::Skipped,
+        Err(e) => return Outcome::Failed(vec![diagnostic::render(&e, &sources)]),
+    };
+
+    let (output, buffer) = Output::buffer();
+    context.output = output;
+    let printed_at = Rc::new(RefCell::new(PrintedAt::default()));
+    let mut functions = default_native_functions();
+    for name in ["print", "dbg"] {
+        record_lines(&mut functions, name, file, &buffer, &printed_at);
+    }
+    let result = Parser::try_new(Lexer::new(source).with_file(file))
+        .and_then(|mut parser| parser.parse())
+        .and_then(|ast| interpret(ast, functions, sources.clone(), context));
+
+    let mut differences = if expected.annotations {
+        annotation_differences(&expected, &printed_at.borrow().lines)
+    } else {
+        let buffer = buffer.borrow();
+        let mut printed = String::from_utf8_lossy(&buffer)
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let last = expected.lines.last();
+        if let (Some((_, last)), Ok(value)) = (last, &result) {
+            if last.starts_with(RESULT) {
+                let result = format!("{}{:#?}", RESULT, value);
+                printed.extend(result.lines().map(str::to_owned));
+            }
+        }
+        differences(&expected, &printed)
+    };
+    if let Err(e) = result {
+        match Exit::code(&e) {
+            Some(0) => {}
+            Some(_) => differences.push(e.to_string()),
+            None => differences.push(diagnostic::render(&e, &sources)),
+        }
+    }
+    if differences.is_empty() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(differences)
+    }
+}
+
+/// Replaces the native `name` with one that also records the lines it
+/// prints to `buffer` in `printed_at`, if it is called from `file`.
+fn record_lines(
+    functions: &mut HashMap<String, NativeFn>,
+    name: &str,
+    file: usize,
+    buffer: &Rc<RefCell<Vec<u8>>>,
+    printed_at: &Rc<RefCell<PrintedAt>>,
+) {
+    let Some(native) = functions.get(name).cloned() else {
+        return;
+    };
+    let (buffer, printed_at) = (buffer.clone(), printed_at.clone());
+    let recorded = NativeFn::new(move |scope, params, span| {
+        let result = native.call(scope, params, span);
+        let buffer = buffer.borrow();
+        let mut printed_at = printed_at.borrow_mut();
+        if span.file == file {
+            let printed = String::from_utf8_lossy(&buffer[printed_at.end..]).into_owned();
+            for line in printed.lines() {
+                printed_at.lines.push((span.line, line.to_owned()));
+            }
+        }
+        printed_at.end = buffer.len();
+        result
+    });
+    functions.insert(name.to_owned(), recorded);
+}
+
+/// Reads what the program at `path` should print from the `.out` file next
+/// to it, or else from its expected output block or annotations. Returns
+/// `None` if it has none of them.
+fn expected_output(path: &str, source: &str, file: usize) -> Result<Option<Expected>> {
+    let out_path = Path::new(path).with_extension("out");
+    if let Ok(out) = fs::read_to_string(&out_path) {
+        return Ok(Some(Expected {
+            path: out_path.display().to_string(),
+            lines: out.lines().enumerate().map(|(i, line)| (i + 1, line.to_owned())).collect(),
+            annotations: false,
+        }));
+    }
+
+    let tokens = Lexer::new(source).with_file(file).parse()?;
+    let (lines, annotations) = match block(&tokens) {
+        Some(lines) => (lines, false),
+        None => (self::annotations(&tokens), true),
+    };
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Expected {
+        path: path.to_owned(),
+        lines,
+        annotations,
+    }))
+}
+
+/// The lines of the first expected output block in `tokens`, without the
+/// blank lines around them.
+fn block(tokens: &[Token]) -> Option<Vec<(usize, String)>> {
+    tokens.iter().find_map(|token| {
+        let TokenType::String(string) = &token.ty else {
+            return None;
+        };
+        let start = string.len() - string.trim_start().len();
+        let rest = string[start..].strip_prefix(BLOCK)?;
+        let first_line = token.span.line + string[..start].matches('\n').count();
+        let mut lines = rest
+            .lines()
+            .enumerate()
+            .skip(1)
+            .map(|(i, line)| (first_line + i, line.to_owned()))
+            .skip_while(|(_, line)| line.trim().is_empty())
+            .collect::<Vec<_>>();
+        while lines.last().is_some_and(|(_, line)| line.trim().is_empty()) {
+            lines.pop();
+        }
+        Some(lines)
+    })
+}
+
+/// The lines given by the annotations in `tokens`, in order.
+fn annotations(tokens: &[Token]) -> Vec<(usize, String)> {
+    tokens
+        .iter()
+        .filter_map(|token| match &token.ty {
+            TokenType::String(string) => {
+                let line = string.strip_prefix(ANNOTATION)?;
+                Some((token.span.line, line.to_owned()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Describes each line of `printed` that isn't the expected one, and each
+/// expected line that wasn't printed.
+fn differences(expected: &Expected, printed: &[String]) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (i, (line, text)) in expected.lines.iter().enumerate() {
+        match printed.get(i) {
+            Some(got) if got == text => {}
+            Some(got) => differences.push(format!(
+                "{}:{}: expected `{}`, got `{}`",
+                expected.path, line, text, got
+            )),
+            None => differences.push(format!(
+                "{}:{}: expected `{}`, but nothing more was printed",
+                expected.path, line, text
+            )),
+        }
+    }
+    for got in printed.iter().skip(expected.lines.len()) {
+        differences.push(format!("unexpected output `{}`", got));
+    }
+    differences
+}
+
+/// Describes how the lines printed by the calls on each annotated line
+/// differ from its annotations. Lines without annotations aren't checked.
+fn annotation_differences(expected: &Expected, printed_at: &[(usize, String)]) -> Vec<String> {
+    let mut lines = expected.lines.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+    lines.dedup();
+    let mut differences = Vec::new();
+    for line in lines {
+        let on_line = |lines: &[(usize, String)]| {
+            lines.iter().filter(|(at, _)| *at == line).cloned().collect::<Vec<_>>()
+        };
+        let expected = Expected {
+            path: expected.path.clone(),
+            lines: on_line(&expected.lines),
+            annotations: true,
+        };
+        let printed = on_line(printed_at).into_iter().map(|(_, text)| text).collect::<Vec<_>>();
+        for difference in self::differences(&expected, &printed) {
+            if difference.starts_with("unexpected") {
+                differences.push(format!("{}:{}: {}", expected.path, line, difference));
+            } else {
+                differences.push(difference);
+            }
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        Lexer::new(source).parse().unwrap()
+    }
+
+    fn lines(lines: &[(usize, &str)]) -> Vec<(usize, String)> {
+        lines.iter().map(|(line, text)| (*line, text.to_string())).collect()
+    }
+
+    #[test]
+    fn test_annotations() {
+        let source = r#"
+            main {
+                (print "a") "Expected: a"
+                "not an annotation"
+                (print 1 2) "Expected: 1" "Expected: 2"
+            }
+        "#;
+        assert_eq!(
+            annotations(&tokens(source)),
+            lines(&[(3, "a"), (5, "1"), (5, "2")])
+        );
+        assert_eq!(block(&tokens(source)), None);
+    }
+
+    #[test]
+    fn test_block() {
+        let source = r#"
+            main { (print 1 "a") }
+
+            "
+            Expected output:
+
+            1
+              a
+            result: Void
+
+            "
+        "#;
+        assert_eq!(
+            block(&tokens(source)),
+            Some(lines(&[
+                (7, "            1"),
+                (8, "              a"),
+                (9, "            result: Void"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_differences() {
+        let expected = Expected {
+            path: "a.thing".to_owned(),
+            lines: lines(&[(1, "x"), (2, "y"), (4, "z")]),
+            annotations: false,
+        };
+        let printed = |text: &str| text.lines().map(str::to_owned).collect::<Vec<_>>();
+        assert!(differences(&expected, &printed("x\ny\nz")).is_empty());
+        assert_eq!(
+            differences(&expected, &printed("x\nwhy")),
+            vec![
+                "a.thing:2: expected `y`, got `why`",
+                "a.thing:4: expected `z`, but nothing more was printed",
+            ]
+        );
+        assert_eq!(
+            differences(&expected, &printed("x\ny\nz\nmore")),
+            vec!["unexpected output `more`"]
+        );
+    }
+
+    #[test]
+    fn test_annotation_differences() {
+        let expected = Expected {
+            path: "a.thing".to_owned(),
+            lines: lines(&[(2, "x"), (3, "y"), (3, "z"), (5, "w")]),
+            annotations: true,
+        };
+        let printed = lines(&[(1, "header"), (2, "x"), (3, "y"), (5, "w"), (5, "v")]);
+        assert_eq!(
+            annotation_differences(&expected, &printed),
+            vec![
+                "a.thing:3: expected `z`, but nothing more was printed",
+                "a.thing:5: unexpected output `v`",
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::diagnostic::Span;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Keyword {
     Fn,
@@ -10,6 +12,11 @@ pub enum Keyword {
     If,
     Else,
     While,
+    For,
+    Return,
+    Break,
+    Continue,
+    Import,
 }
 
 impl TryFrom<&str> for Keyword {
@@ -27,6 +34,11 @@ impl TryFrom<&str> for Keyword {
             "if" => Ok(Self::If),
             "else" => Ok(Self::Else),
             "while" => Ok(Self::While),
+            "for" => Ok(Self::For),
+            "return" => Ok(Self::Return),
+            "break" => Ok(Self::Break),
+            "continue" => Ok(Self::Continue),
+            "import" => Ok(Self::Import),
             _ => Err(()),
         }
     }
@@ -35,8 +47,7 @@ impl TryFrom<&str> for Keyword {
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub ty: TokenType,
-    pub line: usize,
-    pub col: usize,
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -59,6 +70,8 @@ pub enum TokenType {
     RParen,
     LBrace,
     RBrace,
+    /// `#{`, which opens a dict literal.
+    HashLBrace,
     LBracket,
     RBracket,
 }
@@ -0,0 +1,716 @@
+use anyhow::Result;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::ast::AstNode;
+use crate::compiler::{self, CompiledFunction, Op};
+use crate::default_fns::Range;
+use crate::diagnostic::Span;
+use crate::interpreter::{
+    self, Context, DictKey, Environment, InterpreterError, InterpreterScope, InterpreterValue,
+    NativeFn, StackFrame, TailCalls, Traceback,
+};
+
+/// A call to a compiled function in progress.
+struct Frame {
+    function: Rc<CompiledFunction>,
+    /// Where to carry on from once the function it is calling returns.
+    ip: usize,
+    /// Where the function's values start on the stack.
+    base: usize,
+    env: Rc<Environment>,
+    /// Where the function was called from. Top level code has no caller, and
+    /// isn't shown in tracebacks.
+    call: Option<Span>,
+    /// Whether the call was entered in the limits, and has to be left when
+    /// the frame goes. A frame a tail call put in place of top level code
+    /// has a caller but never entered a call.
+    entered: bool,
+    /// The calls this frame replaced with tail calls.
+    tail_calls: TailCalls<Rc<CompiledFunction>>,
+}
+
+/// Runs compiled code on a stack of values. Calls from one compiled function
+/// to another push a frame instead of recursing, so only calls made by
+/// natives use the Rust stack.
+///
+/// Variables live in the same frames as in the tree-walker, so natives and
+/// closures behave the same on both.
+struct Vm {
+    stack: Vec<Rc<InterpreterValue>>,
+    frames: Vec<Frame>,
+    context: Rc<Context>,
+    /// The frame natives read their arguments from, kept between calls.
+    arguments: Option<Rc<Environment>>,
+}
+
+/// Compiles `node` and runs it in the top frame `env`, as `main` and inputs
+/// to the REPL are run.
+pub fn run(
+    node: &AstNode,
+    env: &Rc<Environment>,
+    context: &Rc<Context>,
+) -> Result<Rc<InterpreterValue>> {
+    let frame = Frame {
+        function: Rc::new(compiler::compile_top_level(node)),
+        ip: 0,
+        base: 0,
+        env: env.clone(),
+        call: None,
+        entered: false,
+        tail_calls: TailCalls::default(),
+    };
+    Vm::new(frame, context).run()
+}
+
+/// Calls a compiled function with arguments that have already been
+/// evaluated, as natives taking callbacks do.
+pub fn call(
+    function: &Rc<CompiledFunction>,
+    env: &Rc<Environment>,
+    args: Vec<Rc<InterpreterValue>>,
+    span: Span,
+    context: &Rc<Context>,
+) -> Result<Rc<InterpreterValue>> {
+    if args.len() != function.params.len() {
+        return Err(InterpreterError::InvalidFunctionCall(function.name.clone(), span).into());
+    }
+    context.limits.enter_call(span)?;
+    let frame = Frame {
+        function: function.clone(),
+        ip: 0,
+        base: 0,
+        env: Environment::with_values(env, args),
+        call: Some(span),
+        entered: true,
+        tail_calls: TailCalls::default(),
+    };
+    Vm::new(frame, context).run()
+}
+
+impl Vm {
+    fn new(frame: Frame, context: &Rc<Context>) -> Self {
+        Self {
+            stack: Vec::new(),
+            frames: vec![frame],
+            context: context.clone(),
+            arguments: None,
+        }
+    }
+
+    /// Runs until the first frame returns. Errors carry the calls that were
+    /// in progress, as they do in the tree-walker.
+    fn run(mut self) -> Result<Rc<InterpreterValue>> {
+        self.execute().map_err(|mut error| {
+            for frame in self.frames.drain(..).rev() {
+                if frame.entered {
+                    self.context.limits.leave_call();
+                }
+                if let Some(span) = frame.call {
+                    let name = frame.function.name.clone();
+                    let called = StackFrame {
+                        name,
+                        span,
+                        left_out: 0,
+                    };
+                    error = Traceback::push(error, called);
+                    error = frame.tail_calls.unwind(error, |function| &function.name);
+                }
+            }
+            error
+        })
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn pop(&mut self) -> Rc<InterpreterValue> {
+        self.stack.pop().unwrap()
+    }
+
+    /// Calls a native with the `params.len()` values on top of the stack.
+    /// They are moved into a frame the native reads them from through
+    /// `params`, like `InterpreterScope::call` does. The frame is reused for
+    /// the next call unless the native kept hold of it.
+    fn call_native(
+        &mut self,
+        body: &NativeFn,
+        params: &Vec<AstNode>,
+        span: Span,
+    ) -> Result<Rc<InterpreterValue>> {
+        let env = match self.arguments.take() {
+            Some(env) if Rc::strong_count(&env) == 1 => env,
+            _ => Rc::new(Environment::default()),
+        };
+        env.refill(self.stack.drain(self.stack.len() - params.len()..));
+        let mut scope = InterpreterScope {
+            env,
+            context: self.context.clone(),
+        };
+        let result = body.call(&mut scope, params, span);
+        scope.env.refill([]);
+        self.arguments = Some(scope.env);
+        result
+    }
+
+    fn execute(&mut self) -> Result<Rc<InterpreterValue>> {
+        // The running function and position are kept out of the frame, and
+        // only saved when it calls another function.
+        let mut function = self.frame().function.clone();
+        let mut ip = 0;
+        loop {
+            let chunk = &function.chunk;
+            let at = ip;
+            ip += 1;
+            self.context.limits.step(chunk.spans[at])?;
+            match &chunk.code[at] {
+                Op::Constant(value) => self.stack.push(value.clone()),
+                Op::Load(name, slot) => {
+                    let name = &chunk.names[*name];
+                    let value = self.frame().env.lookup(name, *slot).ok_or_else(|| {
+                        InterpreterError::VariableNotFound(name.clone(), chunk.spans[at])
+                    })?;
+                    self.stack.push(value);
+                }
+                Op::Define(name, slot) => {
+                    let value = self.stack.last().unwrap().clone();
+                    self.frame().env.define(&chunk.names[*name], *slot, value);
+                }
+                Op::DefineConstant(name, slot) => {
+                    let value = self.stack.last().unwrap().clone();
+                    self.frame().env.define_constant(&chunk.names[*name], *slot, value);
+                }
+                Op::Assign(name, slot) => {
+                    let value = self.stack.last().unwrap().clone();
+                    let span = chunk.spans[at];
+                    self.frame().env.replace(&chunk.names[*name], *slot, value, span)?;
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Nip => {
+                    let below = self.stack.len() - 2;
+                    self.stack.swap_remove(below);
+                }
+                Op::Array(len) => {
+                    let values = self.stack.split_off(self.stack.len() - len);
+                    self.stack
+                        .push(Rc::new(InterpreterValue::Array(RefCell::new(values))));
+                }
+                Op::Key => {
+                    DictKey::new(self.stack.last().unwrap(), chunk.spans[at])?;
+                }
+                Op::Dict(len) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * len);
+                    let mut dict = BTreeMap::new();
+                    for entry in entries.chunks(2) {
+                        dict.insert(DictKey::new(&entry[0], chunk.spans[at])?, entry[1].clone());
+                    }
+                    self.stack.push(Rc::new(InterpreterValue::Dict(dict)));
+                }
+                Op::Function(function) => {
+                    let env = self.frame().env.clone();
+                    self.stack.push(Rc::new(InterpreterValue::Compiled {
+                        function: function.clone(),
+                        env,
+                    }));
+                }
+                Op::Callee { name, slot, args } => {
+                    let span = chunk.spans[at];
+                    let name = &chunk.names[*name];
+                    let callee = self.frame().env.lookup(name, *slot).ok_or_else(|| {
+                        InterpreterError::FunctionNotFound(name.clone(), span)
+                    })?;
+                    // Natives check their own arguments. Calling anything
+                    // else with no arguments gives its value.
+                    let expected = match callee.as_ref() {
+                        InterpreterValue::Function { name, params, .. } => {
+                            Some((name, params.len()))
+                        }
+                        InterpreterValue::Compiled { function, .. } => {
+                            Some((&function.name, function.params.len()))
+                        }
+                        InterpreterValue::NativeFunction { .. } => None,
+                        _ => Some((name, 0)),
+                    };
+                    if let Some((name, len)) = expected {
+                        if len != *args {
+                            return Err(
+                                InterpreterError::InvalidFunctionCall(name.clone(), span).into()
+                            );
+                        }
+                    }
+                    self.stack.push(callee);
+                }
+                op @ (Op::Call(params) | Op::TailCall(params)) => {
+                    let span = chunk.spans[at];
+                    let callee = self.stack[self.stack.len() - params.len() - 1].clone();
+                    if let InterpreterValue::NativeFunction { body, .. } = callee.as_ref() {
+                        let value = self.call_native(body, params, span)?;
+                        *self.stack.last_mut().unwrap() = value;
+                        continue;
+                    }
+                    let args = self.stack.split_off(self.stack.len() - params.len());
+                    self.pop();
+                    let InterpreterValue::Compiled { function: called, env } = callee.as_ref()
+                    else {
+                        let value = match callee.as_ref() {
+                            InterpreterValue::Function { .. } => {
+                                let mut scope = InterpreterScope {
+                                    env: self.frame().env.clone(),
+                                    context: self.context.clone(),
+                                };
+                                scope.call(&callee, args, span)?
+                            }
+                            _ => callee,
+                        };
+                        self.stack.push(value);
+                        continue;
+                    };
+                    let frame = Frame {
+                        function: called.clone(),
+                        ip: 0,
+                        base: self.stack.len(),
+                        env: Environment::with_values(env, args),
+                        call: Some(span),
+                        entered: true,
+                        tail_calls: TailCalls::default(),
+                    };
+                    if let Op::TailCall(_) = op {
+                        // The frame is replaced, and only kept for
+                        // tracebacks, as in the tree-walker.
+                        let replaced = self.frame();
+                        let mut tail_calls = std::mem::take(&mut replaced.tail_calls);
+                        if let Some(call) = replaced.call {
+                            tail_calls.push(replaced.function.clone(), call);
+                        }
+                        let (base, entered) = (replaced.base, replaced.entered);
+                        self.stack.truncate(base);
+                        *self.frame() = Frame {
+                            base,
+                            entered,
+                            tail_calls,
+                            ..frame
+                        };
+                    } else {
+                        self.context.limits.enter_call(span)?;
+                        self.frame().ip = ip;
+                        self.frames.push(frame);
+                    }
+                    function = called.clone();
+                    ip = 0;
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    if frame.entered {
+                        self.context.limits.leave_call();
+                    }
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    self.stack.truncate(frame.base);
+                    self.stack.push(value);
+                    function = self.frame().function.clone();
+                    ip = self.frame().ip;
+                }
+                Op::Jump(target) => ip = *target,
+                Op::JumpUnless(target, keyword) => {
+                    let condition = self.pop();
+                    match condition.as_ref() {
+                        InterpreterValue::Bool(true) => {}
+                        InterpreterValue::Bool(false) => ip = *target,
+                        value => {
+                            return Err(InterpreterError::InvalidType1Native(
+                                value.get_type().to_string(),
+                                keyword.to_string(),
+                                chunk.spans[at],
+                            )
+                            .into());
+                        }
+                    }
+                }
+                Op::JumpUnlessNative(name, target) => {
+                    let name = &chunk.names[*name];
+                    match self.stack.last().unwrap().as_ref() {
+                        InterpreterValue::NativeFunction { name: native, .. } if native == name => {
+                            self.pop();
+                        }
+                        _ => ip = *target,
+                    }
+                }
+                Op::Items => {
+                    let value = self.pop();
+                    let items = interpreter::loop_items(&value, chunk.spans[at])?;
+                    self.stack.push(Rc::new(InterpreterValue::Array(RefCell::new(items))));
+                }
+                Op::Range(args) => {
+                    let params = self.stack.split_off(self.stack.len() - args);
+                    let range = Range::new(&params, chunk.spans[at])?;
+                    self.stack.push(Rc::new(InterpreterValue::Int(range.end)));
+                    self.stack.push(Rc::new(InterpreterValue::Int(range.step)));
+                    self.stack.push(Rc::new(InterpreterValue::Int(range.next.unwrap())));
+                }
+                Op::Next(target) => {
+                    let len = self.stack.len();
+                    let loop_state = (
+                        self.stack[len - 4].as_ref(),
+                        self.stack[len - 3].as_ref(),
+                        self.stack[len - 2].as_ref(),
+                    );
+                    let item = match loop_state {
+                        (InterpreterValue::Array(items), _, &InterpreterValue::Int(position)) => {
+                            let item = items.borrow().get(position as usize).cloned();
+                            item.map(|item| (item, Some(position + 1)))
+                        }
+                        (&InterpreterValue::Int(end), &InterpreterValue::Int(step), next) => {
+                            // The position is void once the range has stepped
+                            // past the largest or smallest int.
+                            let next = match *next {
+                                InterpreterValue::Int(next) => Some(next),
+                                _ => None,
+                            };
+                            let mut range = Range { next, end, step };
+                            let item = range.next();
+                            item.map(|n| (Rc::new(InterpreterValue::Int(n)), range.next))
+                        }
+                        _ => unreachable!(),
+                    };
+                    match item {
+                        Some((item, next)) => {
+                            let next = next.map_or(InterpreterValue::Void, InterpreterValue::Int);
+                            self.stack[len - 2] = Rc::new(next);
+                            self.stack.push(item);
+                        }
+                        None => ip = *target,
+                    }
+                }
+                Op::PushFrame => {
+                    let frame = self.frame();
+                    frame.env = Environment::new_child(&frame.env);
+                }
+                Op::PopFrame => {
+                    let frame = self.frame();
+                    frame.env = frame.env.parent().unwrap().clone();
+                }
+                Op::Truncate(height) => {
+                    let base = self.frame().base;
+                    self.stack.truncate(base + height);
+                }
+                Op::Error(error) => return Err((**error).clone().into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        default_fns::default_native_functions, interpreter::interpret, lexer::Lexer,
+        parser::Parser,
+    };
+
+    /// Runs `input` on the tree-walker or the VM, and describes the result,
+    /// or the error along with the calls it unwound through.
+    fn outcome(input: &str, vm: bool) -> String {
+        let mut parser = Parser::try_new(Lexer::new(input)).unwrap();
+        let context = Context {
+            vm,
+            ..Default::default()
+        };
+        let result = interpret(
+            parser.parse().unwrap(),
+            default_native_functions(),
+            Rc::default(),
+            context,
+        );
+        match result {
+            Ok(value) => value.to_string(),
+            Err(error) => match error.downcast_ref::<Traceback>() {
+                Some(traceback) => format!(
+                    "{:?} in {:?}",
+                    traceback.error.downcast_ref::<InterpreterError>(),
+                    traceback.frames
+                ),
+                None => format!("{:?}", error.downcast_ref::<InterpreterError>()),
+            },
+        }
+    }
+
+    /// Checks that both engines agree on `input`, and returns what they gave.
+    fn run_both(input: &str) -> String {
+        let expected = outcome(input, false);
+        assert_eq!(outcome(input, true), expected, "{}", input);
+        expected
+    }
+
+    #[test]
+    fn test_closures() {
+        let result = run_both(r#"
+            fn make_counter() {
+                let count 0
+                fn() {
+                    set count (+ count 1)
+                    count
+                }
+            }
+
+            main {
+                let counter (make_counter)
+                (counter)
+                (counter)
+                [(counter) (map [1 2] fn(x) { (+ x (counter)) })]
+            }
+        "#);
+        assert_eq!(result, "[3, [5, 7]]");
+    }
+
+    #[test]
+    fn test_loops() {
+        let result = run_both(r#"
+            main {
+                let i 0
+                let sum 0
+                let last while true {
+                    set i (+ i 1)
+                    if (== i 3) {
+                        continue
+                    }
+                    if (== i 6) {
+                        break
+                    }
+                    let double (* i 2)
+                    set sum (+ sum double)
+                }
+                [sum last while false { 1 }]
+            }
+        "#);
+        assert_eq!(result, "[24, 24, Void]");
+    }
+
+    #[test]
+    fn test_for_loops() {
+        let result = run_both(r#"
+            fn find(xs, y) {
+                for x in xs {
+                    if (== x y) {
+                        return "found"
+                    }
+                }
+                "missing"
+            }
+
+            main {
+                let sum 0
+                let last for i in (range 10) {
+                    if (== i 3) {
+                        continue
+                    }
+                    if (== i 6) {
+                        break
+                    }
+                    let double (* i 2)
+                    set sum (+ sum double)
+                }
+                let letters []
+                for c in "añb" {
+                    (push letters c)
+                }
+                let items [1 2]
+                let fs []
+                for x in items {
+                    (push items x)
+                    (push fs fn() { x })
+                }
+                let pairs []
+                for x in [1 2] {
+                    for y in [x 3] {
+                        (push pairs (* x y))
+                    }
+                }
+                [sum last letters items (map fs fn(f) { (f) }) pairs (find [1 2] 2) (find [] 1)]
+            }
+        "#);
+        assert_eq!(
+            result,
+            r#"[24, 24, ["a", "ñ", "b"], [1, 2, 1, 2], [1, 2], [1, 3, 4, 6], "found", "missing"]"#
+        );
+    }
+
+    #[test]
+    fn test_for_loops_over_ranges() {
+        let result = run_both(r#"
+            fn first_square_over(n) {
+                for i in (range 1000000000000) {
+                    if (> (* i i) n) {
+                        return i
+                    }
+                }
+            }
+
+            fn shadowed() {
+                let range fn(n) { [n n] }
+                let items []
+                for x in (range 4) {
+                    (push items x)
+                }
+                items
+            }
+
+            main {
+                let max 9223372036854775807
+                let min (- 0 max 1)
+                let ends []
+                for i in (range (- max 3) max 2) {
+                    (push ends i)
+                }
+                for i in (range (+ min 1) min min) {
+                    (push ends i)
+                }
+                let down []
+                for i in (range 10 0 -3) {
+                    if (== i 7) {
+                        continue
+                    }
+                    (push down i)
+                }
+                [(first_square_over 50) ends down (shadowed) for i in (range 0) { i }]
+            }
+        "#);
+        assert_eq!(
+            result,
+            "[8, [9223372036854775804, 9223372036854775806, -9223372036854775807], \
+             [10, 4, 1], [4, 4], Void]"
+        );
+        assert!(run_both("main { for i in (range 1 2 0) { i } }").contains("ZeroRangeStep"));
+    }
+
+    #[test]
+    fn test_collections() {
+        let result = run_both(r#"
+            const OFFSET 10
+
+            main {
+                let xs (map [1 2 3] fn(x) { (+ x OFFSET) })
+                let d #{"first" (get xs 0), "rest" (slice xs 1)}
+                [d (reduce xs + 0) (sort_by xs fn(a, b) { (- b a) })]
+            }
+        "#);
+        assert_eq!(result, r#"[{"first": 11, "rest": [12, 13]}, 36, [13, 12, 11]]"#);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let result = run_both(r#"
+            fn check(calls, x) {
+                (push calls x)
+                (> x 0)
+            }
+
+            fn add(and) {
+                (and 1 2)
+            }
+
+            main {
+                let calls []
+                let results [
+                    (and (check calls 1) (check calls 0) (check calls 2))
+                    (or (check calls 0) (check calls 3) (check calls 4))
+                    (and (check calls 5))
+                    (and (check calls 0) (check calls 6))
+                ]
+                let both or
+                let others [(both (== 1 1) (== 1 1)) (add +) (or (== 1 1) (/ 1 0))]
+                [results calls others]
+            }
+        "#);
+        assert_eq!(result, "[[false, true, true, false], [1, 0, 0, 3, 5, 0], [true, 3, true]]");
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let result = run_both(r#"
+            fn count(n, total) {
+                if (== n 0) {
+                    total
+                } else {
+                    (count (- n 1) (+ total 2))
+                }
+            }
+
+            main {
+                (count 100000 0)
+            }
+        "#);
+        assert_eq!(result, "200000");
+    }
+
+    #[test]
+    fn test_tail_calls_in_tracebacks() {
+        // Lists the frames of an outcome as `name/left_out`, innermost first.
+        let frames = |outcome: String| {
+            outcome
+                .split("StackFrame { name: \"")
+                .skip(1)
+                .map(|frame| {
+                    let name = &frame[..frame.find('"').unwrap()];
+                    let left_out = frame.split("left_out: ").nth(1).unwrap();
+                    format!("{}/{}", name, &left_out[..left_out.find(' ').unwrap()])
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let program = |main: &str| {
+            format!(
+                r#"
+                fn f(x) {{ (+ x "a") }}
+                fn g(x) {{ (f x) }}
+                fn count(n) {{
+                    if (== n 0) {{ (g 1) }} else {{ (count (- n 1)) }}
+                }}
+
+                main {{ {} }}
+                "#,
+                main
+            )
+        };
+        assert_eq!(frames(run_both(&program("(g 1)"))), "f/0 g/0");
+        assert_eq!(
+            frames(run_both(&program("(count 20)"))),
+            "f/0 g/0 count/0 count/0 count/0 count/0 count/0 count/0 count/0 count/13"
+        );
+    }
+
+    #[test]
+    fn test_top_level_return_of_a_call() {
+        // The call leaves the limits as it found them, whether it returns or
+        // fails.
+        assert_eq!(run_both("fn f() { 1 } main { return (f) }"), "1");
+        let failed = run_both(r#"fn f() { (+ 1 "a") } main { return (f) }"#);
+        assert!(failed.contains("name: \"f\""), "{}", failed);
+    }
+
+    #[test]
+    fn test_errors() {
+        for input in [
+            r#"fn f(x) { (+ x "a") } fn g() { (f 1) 2 } main { (g) }"#,
+            "fn apply(g) { (g 1 2) } main { (apply fn(x) { x }) }",
+            r#"fn f(x) { (+ x "a") } main { (map [1] f) }"#,
+            "main { if 1 { 2 } }",
+            r#"main { while "yes" { 2 } }"#,
+            "main { for x in 1 { x } }",
+            "main { #{[1] 2} }",
+            "main { (get [1] 1) }",
+            "main { const x 1 fn f() { set x 2 } (f) }",
+            "main { fn f() { set y 2 } (f) let y 1 }",
+            "fn f(x) { (/ 10 x) } main { (f 0) }",
+            "main { (* 4611686018427387904 2) }",
+            "main { (and (== 1 1) 2) }",
+            "main { (or (== 1 2) (/ 1 0)) }",
+        ] {
+            let outcome = run_both(input);
+            assert!(outcome.contains("Span"), "{}: {}", input, outcome);
+        }
+    }
+}
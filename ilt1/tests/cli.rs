@@ -0,0 +1,30 @@
+//! Runs the `ilt1` binary on programs given on stdin.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], program: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ilt1"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(program.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_deep_recursion_stops_at_the_call_depth_limit() {
+    let program = r#"
+        fn deep(n) { if (== n 0) { 0 } else { (+ 1 (deep (- n 1))) } }
+        main { (deep 100000) }
+    "#;
+    for args in [&[][..], &["--vm"], &["--max-depth", "2000"]] {
+        let output = run(args, program);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("calls in progress"), "{:?}: {}", args, stderr);
+    }
+}
@@ -0,0 +1,209 @@
+//! Embeds the language through `Engine`, as a host program would.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ilt1::{
+    Context, Engine, EngineError, InterpreterError, InterpreterValue, Limits, Output,
+};
+
+#[test]
+fn test_natives_keep_state() {
+    let count = Rc::new(Cell::new(0));
+    let mut engine = Engine::new();
+    let counter = count.clone();
+    engine.register_fn("tick", move |by: i64| {
+        counter.set(counter.get() + by);
+        counter.get()
+    });
+
+    let last: i64 = engine.eval_str("(tick 1) (tick 2) (tick 3)").unwrap();
+    assert_eq!(last, 6);
+    assert_eq!(count.get(), 6);
+}
+
+#[test]
+fn test_conversions() {
+    let mut engine = Engine::new();
+    engine
+        .register_fn("mean", |xs: Vec<f64>| xs.iter().sum::<f64>() / xs.len() as f64)
+        .register_fn("words", |s: String| {
+            s.split_whitespace().map(str::to_owned).collect::<Vec<_>>()
+        })
+        .register_fn("nothing", || ());
+
+    assert_eq!(engine.eval_str::<f64>("(mean [1 2.5 4.5])").unwrap(), 8.0 / 3.0);
+    let words: Vec<String> = engine.eval_str(r#"(words "a b  c")"#).unwrap();
+    assert_eq!(words, ["a", "b", "c"]);
+    let value: Rc<InterpreterValue> = engine.eval_str("(nothing)").unwrap();
+    assert!(matches!(value.as_ref(), InterpreterValue::Void));
+}
+
+#[test]
+fn test_call_function() {
+    let mut engine = Engine::new();
+    engine
+        .eval_str::<()>(
+            r#"
+            let greeting "hello"
+            fn greet(name, times) {
+                (join (map (range times) fn(i) { (format "{} {}" greeting name) }) ", ")
+            }
+            "#,
+        )
+        .unwrap();
+
+    let greeted: String = engine.call_function("greet", ("bob", 2)).unwrap();
+    assert_eq!(greeted, "hello bob, hello bob");
+    let sum: i64 = engine.call_function("+", (1, 2, 3)).unwrap();
+    assert_eq!(sum, 6);
+
+    let error = engine.call_function::<i64>("greet", ("bob", 1)).unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(EngineError::InvalidReturnType(..))));
+    let error = engine.call_function::<()>("missing", ()).unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(EngineError::FunctionNotFound(_))));
+}
+
+#[test]
+fn test_native_errors() {
+    let mut engine = Engine::new();
+    engine.register_fn("parse", |s: String| {
+        s.parse::<i64>().map_err(|e| anyhow!("can't parse {:?}: {}", s, e))
+    });
+
+    assert_eq!(engine.eval_str::<i64>(r#"(parse "42")"#).unwrap(), 42);
+    let error = engine.eval_str::<i64>(r#"(parse "x")"#).unwrap_err();
+    assert!(error.to_string().contains("can't parse \"x\""), "{}", error);
+
+    let error = engine.eval_str::<i64>("(parse 42)").unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(InterpreterError::InvalidTypeArgNative(..))));
+    assert!(engine.render_error(&error).contains("(parse 42)"));
+    let error = engine.eval_str::<i64>(r#"(parse "1" "2")"#).unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(InterpreterError::InvalidFunctionCall(..))));
+}
+
+#[test]
+fn test_vm_and_captured_output() {
+    let (output, printed) = Output::buffer();
+    let context = Context {
+        vm: true,
+        output,
+        ..Default::default()
+    };
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut engine = Engine::with_context(context);
+    let logged = log.clone();
+    engine.register_fn("log", move |line: String| logged.borrow_mut().push(line));
+
+    engine
+        .eval_str::<()>(r#"(for_each [1 2] fn(x) { (log (string x)) (print x) })"#)
+        .unwrap();
+    assert_eq!(*log.borrow(), ["1", "2"]);
+    assert_eq!(String::from_utf8_lossy(&printed.borrow()), "1\n2\n");
+}
+
+#[test]
+fn test_captured_warnings() {
+    let (warnings, written) = Output::buffer();
+    let context = Context {
+        warn_shadowing: true,
+        warnings: Some(warnings),
+        ..Default::default()
+    };
+    let mut engine = Engine::with_context(context);
+    engine.eval_str::<()>("fn f(x) { let x 1 x }").unwrap();
+    let written = String::from_utf8_lossy(&written.borrow()).into_owned();
+    assert!(written.contains("warning"), "{}", written);
+    assert!(written.contains("let x 1"), "{}", written);
+
+    // Without anywhere to write them, warnings are dropped.
+    let mut engine = Engine::with_context(Context {
+        warn_shadowing: true,
+        ..Default::default()
+    });
+    engine.eval_str::<()>("fn f(x) { let x 1 x }").unwrap();
+}
+
+#[test]
+fn test_limits() {
+    for vm in [false, true] {
+        let limits = Limits::default()
+            .with_fuel(100_000)
+            .with_max_depth(20)
+            .with_timeout(Duration::from_secs(60));
+        let mut engine = Engine::with_context(Context {
+            vm,
+            limits,
+            ..Default::default()
+        });
+        engine
+            .eval_str::<()>("fn deep(n) { if (== n 0) { 0 } else { (+ 1 (deep (- n 1))) } }")
+            .unwrap();
+
+        let error = engine.eval_str::<()>("while true {}").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(InterpreterError::OutOfFuel(100_000, _))));
+        assert_eq!(engine.eval_str::<i64>("(deep 19)").unwrap(), 19);
+        let error = engine.call_function::<i64>("deep", (20,)).unwrap_err();
+        assert!(matches!(
+            InterpreterError::limit(&error),
+            Some(InterpreterError::CallDepthExceeded(20, _))
+        ));
+        // Each run gets all of its fuel again, and the calls that failed are
+        // no longer in progress.
+        assert_eq!(engine.call_function::<i64>("deep", (19,)).unwrap(), 19);
+        assert_eq!(engine.eval_str::<i64>("(len (range 1000))").unwrap(), 1000);
+        // Natives use fuel for each element, and a range too long for it
+        // isn't made.
+        let error = engine.eval_str::<()>("(range 1000000000000)").unwrap_err();
+        assert!(matches!(InterpreterError::limit(&error), Some(InterpreterError::OutOfFuel(..))));
+        let error = engine.eval_str::<()>("(map (range 60000) string)").unwrap_err();
+        assert!(matches!(InterpreterError::limit(&error), Some(InterpreterError::OutOfFuel(..))));
+        // A loop over a range goes through it without making it.
+        engine
+            .eval_str::<()>(
+                "fn first_square_over(n) { \
+                     for i in (range 1000000000000) { if (> (* i i) n) { return i } } \
+                 }",
+            )
+            .unwrap();
+        assert_eq!(engine.call_function::<i64>("first_square_over", (50,)).unwrap(), 8);
+        let error = engine.eval_str::<()>("(+ 1 true)").unwrap_err();
+        assert!(InterpreterError::limit(&error).is_none());
+
+        let limits = Limits::default().with_timeout(Duration::from_millis(50));
+        let mut engine = Engine::with_context(Context {
+            vm,
+            limits,
+            ..Default::default()
+        });
+        let error = engine.eval_str::<()>("while true {}").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(InterpreterError::Timeout(..))));
+    }
+}
+
+#[test]
+fn test_default_max_depth() {
+    // Deep recursion in the tree-walker needs more than a test thread's stack.
+    let deep = thread::Builder::new().stack_size(ilt1::limits::STACK_SIZE).spawn(|| {
+        for vm in [false, true] {
+            let mut engine = Engine::with_context(Context {
+                vm,
+                ..Default::default()
+            });
+            engine
+                .eval_str::<()>("fn deep(n) { if (== n 0) { 0 } else { (+ 1 (deep (- n 1))) } }")
+                .unwrap();
+            let depth = ilt1::limits::DEFAULT_MAX_DEPTH as i64;
+            assert_eq!(engine.call_function::<i64>("deep", (depth - 1,)).unwrap(), depth - 1);
+            let error = engine.call_function::<i64>("deep", (depth,)).unwrap_err();
+            assert!(matches!(
+                InterpreterError::limit(&error),
+                Some(InterpreterError::CallDepthExceeded(..))
+            ));
+        }
+    });
+    deep.unwrap().join().unwrap();
+}
@@ -0,0 +1,53 @@
+//! Runs `ilt1 test` on programs with annotations and `.out` files.
+
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+
+fn run_tests(dir: &str, paths: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ilt1"))
+        .current_dir(dir)
+        .arg("test")
+        .args(paths)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_examples_pass() {
+    let examples = ["conversions.thing", "examples.thing", "hi.thing"];
+    let output = run_tests(env!("CARGO_MANIFEST_DIR"), &examples);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.ends_with("3 passed, 0 failed, 0 skipped\n"), "{}", stdout);
+}
+
+#[test]
+fn test_out_files() {
+    let dir = env::temp_dir().join(format!("ilt1-test-runner-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("good.thing"), "main { (print (+ 1 2) \"x\") }").unwrap();
+    fs::write(dir.join("good.out"), "3\nx\n").unwrap();
+    fs::write(dir.join("bad.thing"), "main { (print 1) (print (/ 1 0)) }").unwrap();
+    fs::write(dir.join("bad.out"), "2\n3\n").unwrap();
+    fs::write(dir.join("none.thing"), "main { 1 }").unwrap();
+    fs::write(
+        dir.join("annotated.thing"),
+        "fn f() { (print \"f\") 1 }\nmain {\n    (print (f)) \"Expected: 2\"\n}",
+    )
+    .unwrap();
+
+    let paths = ["good.thing", "bad.thing", "none.thing", "annotated.thing"];
+    let output = run_tests(dir.to_str().unwrap(), &paths);
+    fs::remove_dir_all(&dir).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(stdout.contains("PASS good.thing"), "{}", stdout);
+    assert!(stdout.contains("FAIL bad.thing"), "{}", stdout);
+    assert!(stdout.contains("bad.out:1: expected `2`, got `1`"), "{}", stdout);
+    assert!(stdout.contains("bad.out:2: expected `3`, but nothing more"), "{}", stdout);
+    assert!(stdout.contains("Division by zero"), "{}", stdout);
+    assert!(stdout.contains("SKIP none.thing"), "{}", stdout);
+    assert!(stdout.contains("annotated.thing:3: expected `2`, got `1`"), "{}", stdout);
+    assert!(stdout.ends_with("1 passed, 2 failed, 1 skipped\n"), "{}", stdout);
+}
@@ -0,0 +1,87 @@
+//! Runs the example programs on both the tree-walker and the VM, and checks
+//! that they behave the same.
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(path: &str, vm: bool) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ilt1"));
+    command.current_dir(env!("CARGO_MANIFEST_DIR"));
+    if vm {
+        command.arg("--vm");
+    }
+    command.arg(path).output().unwrap()
+}
+
+/// Runs `program`, given on stdin.
+fn run_stdin(program: &str, vm: bool) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ilt1"));
+    if vm {
+        command.arg("--vm");
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(program.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn assert_same(actual: Output, expected: Output, name: &str) {
+    assert_eq!(actual.status, expected.status, "{}", name);
+    assert_eq!(normalize(&actual.stdout), normalize(&expected.stdout), "{}", name);
+    assert_eq!(
+        String::from_utf8_lossy(&actual.stderr),
+        String::from_utf8_lossy(&expected.stderr),
+        "{}",
+        name
+    );
+}
+
+/// The final `result:` dump shows how a function is represented, which is
+/// meant to differ between the engines.
+fn normalize(stdout: &[u8]) -> String {
+    let stdout = String::from_utf8_lossy(stdout);
+    for dump in ["result: Function {", "result: Compiled {"] {
+        if let Some(start) = stdout.find(dump) {
+            return format!("{}result: <function>", &stdout[..start]);
+        }
+    }
+    stdout.into_owned()
+}
+
+#[test]
+fn test_examples_match_tree_walker() {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(env!("CARGO_MANIFEST_DIR")).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        // The benchmark only repeats examples.thing, slowly.
+        if name.ends_with(".thing") && name != "bench.thing" {
+            paths.push(name);
+        }
+    }
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        assert_same(run(&path, true), run(&path, false), &path);
+    }
+}
+
+/// Shapes of program that no example file has.
+#[test]
+fn test_programs_match_tree_walker() {
+    for program in [
+        "fn f() { 1 } main { return (f) }",
+        r#"fn f() { (+ 1 "a") } main { return (f) }"#,
+        // The call returned from the top level counts towards the limit on
+        // calls in progress.
+        "fn deep(n) { if (== n 0) { 0 } else { (+ 1 (deep (- n 1))) } } \
+         main { return (deep 1000) }",
+    ] {
+        assert_same(run_stdin(program, true), run_stdin(program, false), program);
+    }
+}